@@ -0,0 +1,407 @@
+use std::error::Error;
+
+use discord_rich_presence::activity::{
+    Activity, ActivityType, Assets, Button, Party, Timestamps,
+};
+use log::warn;
+use urlencoding::encode;
+
+use crate::{
+    App, Config, Layout, MAX_BUTTONS, fmt_duration, player_icon, track_info::TrackInfo,
+};
+
+/// An activity rendered from a track, owning its texts until it is sent
+#[derive(Clone)]
+pub struct RenderedActivity {
+    pub activity_type: ActivityType,
+    pub details: Option<String>,
+    pub state: Option<String>,
+    /// Url of the cover, empty while it is being uploaded. Without it, there are no
+    /// images at all.
+    pub large_image: Option<String>,
+    pub large_text: Option<String>,
+    pub small_image: Option<String>,
+    pub small_text: Option<String>,
+    /// Labels and urls
+    pub buttons: Vec<(String, String)>,
+    /// When the track started playing, in milliseconds since the epoch. `None` while
+    /// paused, as Discord can't stop a running timer.
+    pub start: Option<i64>,
+    /// When the track ends, in milliseconds since the epoch. Live streams have none.
+    pub end: Option<i64>,
+    pub party_id: Option<String>,
+    /// Number of listeners in the party, and its maximum
+    pub party_size: Option<[i32; 2]>,
+}
+
+impl RenderedActivity {
+    /// The activity to send to Discord, borrowing the texts
+    pub fn activity(&self) -> Activity<'_> {
+        let mut activity = Activity::new().activity_type(self.activity_type.clone());
+        if let Some(details) = &self.details {
+            activity = activity.details(details);
+        }
+        if let Some(state) = &self.state {
+            activity = activity.state(state);
+        }
+        if let Some(image) = &self.large_image {
+            let mut assets = Assets::new().large_image(image);
+            if let Some(text) = &self.large_text {
+                assets = assets.large_text(text);
+            }
+            if let Some(image) = &self.small_image {
+                assets = assets.small_image(image);
+            }
+            if let Some(text) = &self.small_text {
+                assets = assets.small_text(text);
+            }
+            activity = activity.assets(assets);
+        }
+        if !self.buttons.is_empty() {
+            let buttons = self
+                .buttons
+                .iter()
+                .map(|(label, url)| Button::new(label, url))
+                .collect();
+            activity = activity.buttons(buttons);
+        }
+        if self.party_id.is_some() || self.party_size.is_some() {
+            let mut party = Party::new();
+            if let Some(id) = &self.party_id {
+                party = party.id(id);
+            }
+            if let Some(size) = self.party_size {
+                party = party.size(size);
+            }
+            activity = activity.party(party);
+        }
+        if let Some(start) = self.start {
+            let mut timestamps = Timestamps::new().start(start);
+            if let Some(end) = self.end {
+                timestamps = timestamps.end(end);
+            }
+            activity = activity.timestamps(timestamps);
+        }
+        activity
+    }
+}
+
+/// Renders tracks into Discord activities like a
+/// [`PresenceService`](crate::PresenceService) does, following the formats, layout
+/// and buttons of a [`Config`]. Useful to send them with a Discord client of your
+/// own.
+///
+/// ```
+/// use music_presence::{ActivityRenderer, Config, TrackInfo};
+///
+/// let config: Config = "state_format = \"{artist} · {album}\"".parse().unwrap();
+/// let renderer = ActivityRenderer::new(config).unwrap();
+/// let mut track = TrackInfo::new("Song", vec!["Artist".into()], "Album", "", "player");
+/// track.length = 180_000_000;
+/// let rendered = renderer.render(&track);
+/// assert_eq!(rendered.state.as_deref(), Some("Artist · Album"));
+/// ```
+pub struct ActivityRenderer {
+    app: App,
+}
+
+impl ActivityRenderer {
+    /// Fails if the config is invalid
+    pub fn new(config: Config) -> Result<Self, Box<dyn Error>> {
+        Ok(ActivityRenderer {
+            app: App::from_config(Some(config))?,
+        })
+    }
+
+    pub fn render(&self, track: &TrackInfo) -> RenderedActivity {
+        self.app.render_activity(track)
+    }
+
+    /// The activity shown while nothing is playing, see `idle_details`
+    pub fn render_idle(&self) -> RenderedActivity {
+        self.app.render_idle()
+    }
+}
+
+impl App {
+    /// Renders `track` as set up by the formatting options
+    pub(crate) fn render_activity(&self, track: &TrackInfo) -> RenderedActivity {
+        // Without an album, `album-first` is the same as `classic`
+        let layout = match self.layout {
+            Layout::AlbumFirst if track.album.is_empty() => Layout::Classic,
+            layout => layout,
+        };
+        let details = match &self.details_format {
+            Some(template) => track.render(template),
+            None if layout == Layout::AlbumFirst => track.album.clone(),
+            None => track.title.clone(),
+        };
+        let show_state = self.state_format.is_some() || layout != Layout::TitleOnly;
+        let mut state = match &self.state_format {
+            Some(template) => track.render(template),
+            None if layout == Layout::AlbumFirst && !track.artist.is_empty() => {
+                format!("{} by {}", track.title, track.artist)
+            }
+            None if layout == Layout::AlbumFirst => track.title.clone(),
+            None if layout == Layout::TitleOnly => String::new(),
+            // Podcasts and streams often lack the artist, the album or both
+            None => [
+                (&self.artist_label, &track.artist),
+                (&self.album_label, &track.album),
+            ]
+            .into_iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(label, value)| match label.is_empty() {
+                true => value.clone(),
+                false => format!("{label} {value}"),
+            })
+            .collect::<Vec<_>>()
+            .join(", "),
+        };
+        let paused_format = self
+            .paused_state_format
+            .as_deref()
+            .filter(|_| track.is_paused());
+        if let Some(template) = paused_format {
+            state = track
+                .render(template)
+                .replace("{position}", &fmt_duration(track.position));
+        } else {
+            if show_state && track.is_live() && !self.live_text.is_empty() {
+                state.push_str(&format!(" ({})", self.live_text));
+            }
+            if show_state && track.is_paused() {
+                state.push_str(&format!(" (paused at {})", fmt_duration(track.position)));
+            }
+        }
+
+        // When the track actually started playing, if the player reports its position.
+        // Discord's timestamps are in milliseconds like `start`, while the player
+        // reports microseconds.
+        let start = track.start - track.position / 1000;
+        // Live streams only get the elapsed time, as there is no end
+        let end = (!track.is_live()).then(|| start + track.length / 1000);
+
+        let title = match self.search_raw_title {
+            true => &track.raw_title,
+            false => &track.title,
+        };
+        let fmt = match &self.search_query_format {
+            Some(template) => {
+                let mut track = track.clone();
+                track.title.clone_from(title);
+                track.render(template)
+            }
+            None => {
+                // Only the primary artist, more tend to worsen the search results
+                let artist = track.artists.first().map_or("", String::as_str);
+                format!("{title} {artist}")
+            }
+        };
+        let query = encode(fmt.trim());
+        let url = self.search_url.replace("{query}", &query);
+
+        let mut buttons = vec![];
+        if !self.hide_listen_button {
+            let label = Self::fit_field("button label", &self.listen_button_text, 32);
+            buttons.push((label, url));
+        }
+        if !self.hide_repository_button {
+            buttons.push((
+                "View repository".to_string(),
+                "https://github.com/faervan/music_presence".to_string(),
+            ));
+        }
+        buttons.extend(self.button.iter().map(|button| {
+            let label = track.render(&button.label);
+            (
+                Self::fit_field("button label", &label, 32),
+                track.render_url(&button.url),
+            )
+        }));
+        if self.no_buttons {
+            buttons.clear();
+        }
+        if buttons.len() > MAX_BUTTONS {
+            for (label, _) in buttons.drain(MAX_BUTTONS..) {
+                warn!("Discord allows at most {MAX_BUTTONS} buttons, dropping `{label}`");
+            }
+        }
+
+        let large_text = match &self.large_image_text {
+            Some(template) => track.render(template),
+            None if !track.album.is_empty() => track.album.clone(),
+            None => track.artist.clone(),
+        };
+        let large_text = (!large_text.is_empty())
+            .then(|| Self::fit_field("large image text", &large_text, 128));
+
+        // Local covers can only be shown once uploaded, which might have failed
+        let art_url = &track.art_url;
+        let has_cover = art_url.starts_with("https://") || art_url.starts_with("http://");
+        let large_image = match self.default_cover.as_deref() {
+            _ if has_cover => art_url,
+            Some("player") => player_icon(&track.player).unwrap_or_default(),
+            Some(url) => url,
+            // Not uploaded (yet), Discord can't show it
+            None => "",
+        };
+        let large_image = self.static_large_image.as_deref().unwrap_or(large_image);
+        let small_image = self.small_image.as_deref().and_then(|image| match image {
+            "player" => player_icon(&track.player),
+            url => Some(url),
+        });
+        let small_text = self
+            .small_image_text
+            .as_ref()
+            .map(|template| track.render(template))
+            .filter(|text| !text.trim().is_empty())
+            .map(|text| Self::fit_field("small image text", &text, 128));
+
+        // Discord dislikes empty fields, so they are left out entirely
+        let state = (!state.trim().is_empty())
+            .then(|| Self::fit_field("state", state.trim(), 128));
+        let details = (!details.trim().is_empty())
+            .then(|| Self::fit_field("details", &details, 128));
+
+        let party_size = self
+            .party_size
+            .zip(self.party_max)
+            .map(|(size, max)| [size.min(max).into(), max.into()]);
+        // Discord can't stop a running timer, so paused tracks get none at all
+        let paused = track.is_paused();
+        RenderedActivity {
+            activity_type: self.activity_type.clone(),
+            details,
+            state,
+            large_image: Some(large_image.to_string()),
+            large_text,
+            small_image: small_image.map(String::from),
+            small_text,
+            buttons,
+            start: (!paused).then_some(start),
+            end: end.filter(|_| !paused),
+            party_id: self.party_id.clone(),
+            party_size,
+        }
+    }
+
+    /// Renders `--idle-details` and `--idle-state`, with the default cover if there
+    /// is one
+    pub(crate) fn render_idle(&self) -> RenderedActivity {
+        let details = self
+            .idle_details
+            .as_deref()
+            .map(|text| Self::fit_field("idle details", text, 128));
+        let state = self
+            .idle_state
+            .as_deref()
+            .map(|text| Self::fit_field("idle state", text, 128));
+        let cover = self
+            .static_large_image
+            .as_deref()
+            .or(self.default_cover.as_deref().filter(|url| *url != "player"));
+        RenderedActivity {
+            activity_type: self.activity_type.clone(),
+            details,
+            state,
+            large_image: cover.map(String::from),
+            large_text: None,
+            small_image: None,
+            small_text: None,
+            buttons: vec![],
+            start: None,
+            end: None,
+            party_id: None,
+            party_size: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    fn track(length_ms: i64) -> TrackInfo {
+        let mut track =
+            TrackInfo::new("Song", vec!["Artist".into()], "Album", "", "player");
+        track.length = length_ms * 1000;
+        track
+    }
+
+    /// The timestamps as sent to Discord
+    fn timestamps(rendered: &RenderedActivity) -> Value {
+        serde_json::to_value(rendered.activity()).unwrap()["timestamps"].clone()
+    }
+
+    #[test]
+    fn progress_bar_spans_the_track() {
+        let app = App::from_config(None).unwrap();
+        let mut track = track(180_000);
+        track.position = 60_000_000;
+        let timestamps = timestamps(&app.render_activity(&track));
+        let start = timestamps["start"].as_i64().unwrap();
+        let end = timestamps["end"].as_i64().unwrap();
+        assert_eq!(start, track.start - 60_000);
+        assert_eq!(end - start, track.length / 1000);
+        assert_eq!(end - start, 3 * 60 * 1000);
+    }
+
+    #[test]
+    fn live_streams_have_no_end() {
+        let app = App::from_config(None).unwrap();
+        let track = track(0);
+        let rendered = app.render_activity(&track);
+        let timestamps = timestamps(&rendered);
+        assert_eq!(timestamps["start"].as_i64(), Some(track.start));
+        assert!(timestamps["end"].is_null(), "{timestamps}");
+        assert!(rendered.state.unwrap().ends_with(" (LIVE)"));
+    }
+
+    #[test]
+    fn state_leaves_out_missing_fields() {
+        let app = App::from_config(None).unwrap();
+        let state = |artist: &str, album: &str| {
+            let mut track = track(180_000);
+            track.artist = artist.to_string();
+            track.album = album.to_string();
+            app.render_activity(&track).state
+        };
+        assert_eq!(
+            state("Artist", "Album").as_deref(),
+            Some("by: Artist, in: Album")
+        );
+        assert_eq!(state("Artist", "").as_deref(), Some("by: Artist"));
+        assert_eq!(state("", "Album").as_deref(), Some("in: Album"));
+        assert_eq!(state("", ""), None);
+    }
+
+    #[test]
+    fn empty_state_is_not_sent() {
+        let app = App::from_config(None).unwrap();
+        let track = TrackInfo {
+            length: 180_000_000,
+            ..TrackInfo::new("Episode", vec![], "", "", "player")
+        };
+        let rendered = app.render_activity(&track);
+        let activity = serde_json::to_value(rendered.activity()).unwrap();
+        assert!(activity["state"].is_null(), "{activity}");
+        assert_eq!(activity["details"], "Episode");
+    }
+
+    #[test]
+    fn player_reaches_the_activity() {
+        let config: Config = "details_format = \"{title} on {player}\"\n\
+            small_image_text = \"via {player}\""
+            .parse()
+            .unwrap();
+        let app = App::from_config(Some(config)).unwrap();
+        let mut track = track(180_000);
+        track.player = "spotify.instance42".to_string();
+        let rendered = app.render_activity(&track);
+        assert_eq!(rendered.details.as_deref(), Some("Song on spotify"));
+        assert_eq!(rendered.small_text.as_deref(), Some("via spotify"));
+    }
+}
@@ -0,0 +1,41 @@
+use std::ffi::OsStr;
+
+use clap::builder::{PossibleValue, TypedValueParser};
+use discord_rich_presence::activity::ActivityType;
+
+const NAMES: [&str; 4] = ["listening", "playing", "watching", "competing"];
+
+#[derive(Clone)]
+pub struct ActivityTypeParser;
+
+impl TypedValueParser for ActivityTypeParser {
+    type Value = ActivityType;
+
+    fn parse_ref(
+        &self,
+        _cmd: &clap::Command,
+        _arg: Option<&clap::Arg>,
+        value: &OsStr,
+    ) -> Result<Self::Value, clap::Error> {
+        let value = value
+            .to_str()
+            .ok_or(clap::Error::new(clap::error::ErrorKind::InvalidUtf8))?;
+        parse_activity_type(value)
+            .ok_or(clap::Error::new(clap::error::ErrorKind::InvalidValue))
+    }
+
+    fn possible_values(&self) -> Option<Box<dyn Iterator<Item = PossibleValue> + '_>> {
+        Some(Box::new(NAMES.into_iter().map(PossibleValue::new)))
+    }
+}
+
+/// Parses one of `listening`, `playing`, `watching` or `competing`
+pub fn parse_activity_type(value: &str) -> Option<ActivityType> {
+    match value {
+        "listening" => Some(ActivityType::Listening),
+        "playing" => Some(ActivityType::Playing),
+        "watching" => Some(ActivityType::Watching),
+        "competing" => Some(ActivityType::Competing),
+        _ => None,
+    }
+}
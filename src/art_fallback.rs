@@ -0,0 +1,152 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{Arc, Mutex},
+};
+
+use clap::ValueEnum;
+use log::{info, warn};
+use reqwest::{Client, header::USER_AGENT};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{TrackUpdate, track_info::TrackInfo};
+
+/// MusicBrainz asks clients to identify themselves, see
+/// https://musicbrainz.org/doc/MusicBrainz_API/Rate_Limiting
+const USER_AGENT_VALUE: &str = concat!(
+    "music_presence/",
+    env!("CARGO_PKG_VERSION"),
+    " ( https://github.com/faervan/music_presence )"
+);
+/// Search results scoring lower are likely a different album
+const MIN_SCORE: u64 = 90;
+
+#[derive(Clone, Copy, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArtFallback {
+    /// musicbrainz.org and coverartarchive.org
+    Musicbrainz,
+}
+
+/// Looks up covers of tracks without one by their artist and album
+pub struct CoverLookup {
+    provider: ArtFallback,
+    client: Client,
+    /// Covers by artist and album. `None` if there is none, or the lookup is
+    /// still running.
+    cache: Mutex<HashMap<(String, String), Option<String>>>,
+}
+
+impl CoverLookup {
+    pub fn new(provider: ArtFallback) -> Self {
+        CoverLookup {
+            provider,
+            client: Client::new(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The cover found for the album of `track` before. Starts looking it up if
+    /// that never happened, sending [`TrackUpdate::ImageUploaded`] once found.
+    pub fn cover(
+        self: &Arc<Self>,
+        track: &TrackInfo,
+        sender: &UnboundedSender<TrackUpdate>,
+    ) -> Option<String> {
+        if track.artist.is_empty() || track.album.is_empty() {
+            return None;
+        }
+        let key = (track.artist.clone(), track.album.clone());
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(url) = cache.get(&key) {
+            return url.clone();
+        }
+        cache.insert(key.clone(), None);
+        drop(cache);
+
+        let lookup = self.clone();
+        let sender = sender.clone();
+        let track = track.clone();
+        tokio::spawn(async move {
+            let (artist, album) = &key;
+            match lookup.lookup(artist, album).await {
+                Ok(Some(url)) => {
+                    info!("Found cover of {album} by {artist}: {url}");
+                    lookup.cache.lock().unwrap().insert(key, Some(url.clone()));
+                    let _ = sender.send(TrackUpdate::ImageUploaded { track, url });
+                }
+                Ok(None) => info!("No cover found for {album} by {artist}"),
+                Err(e) => {
+                    warn!("Failed to look up the cover of {album} by {artist}: {e}");
+                    // Try again when the album is played the next time
+                    lookup.cache.lock().unwrap().remove(&key);
+                }
+            }
+        });
+        None
+    }
+
+    async fn lookup(
+        &self,
+        artist: &str,
+        album: &str,
+    ) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        match self.provider {
+            ArtFallback::Musicbrainz => self.musicbrainz(artist, album).await,
+        }
+    }
+
+    /// Searches MusicBrainz for the release group and takes its front cover from
+    /// the Cover Art Archive, see https://musicbrainz.org/doc/MusicBrainz_API/Search
+    /// and https://musicbrainz.org/doc/Cover_Art_Archive/API
+    async fn musicbrainz(
+        &self,
+        artist: &str,
+        album: &str,
+    ) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        let query = format!(
+            "releasegroup:{} AND artist:{}",
+            phrase(album),
+            phrase(artist)
+        );
+        let response: Value = self
+            .client
+            .get("https://musicbrainz.org/ws/2/release-group")
+            .header(USER_AGENT, USER_AGENT_VALUE)
+            .query(&[("query", query.as_str()), ("fmt", "json"), ("limit", "1")])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let Some(id) = response["release-groups"]
+            .as_array()
+            .and_then(|groups| groups.first())
+            .filter(|group| group["score"].as_u64().unwrap_or_default() >= MIN_SCORE)
+            .and_then(|group| group["id"].as_str())
+        else {
+            return Ok(None);
+        };
+
+        // Redirects to the image on archive.org, or 404s if there is none
+        let response = self
+            .client
+            .head(format!(
+                "https://coverartarchive.org/release-group/{id}/front-250"
+            ))
+            .header(USER_AGENT, USER_AGENT_VALUE)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Ok(None);
+        }
+        Ok(Some(response.url().to_string()))
+    }
+}
+
+/// Quotes `text` as a Lucene phrase
+fn phrase(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
@@ -0,0 +1,368 @@
+use std::{
+    error::Error,
+    fs,
+    io::ErrorKind,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use clap::{ArgMatches, parser::ValueSource};
+use log::debug;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{
+    App, Layout, Source,
+    activity_type_parser::parse_activity_type,
+    art_fallback::ArtFallback,
+    image_host::Host,
+    parse_app_id, parse_button, parse_regex, parse_search_url,
+    resize::{CoverFormat, ResizeFilter},
+    size_parser::parse_size,
+};
+
+/// Options read from the config file. Every key maps to the CLI flag of the same
+/// name, which takes precedence if both are present. Libraries can build one with
+/// [`Config::set`] instead.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    retries: Option<usize>,
+    connect_timeout: Option<u64>,
+    retry_base_ms: Option<u64>,
+    retry_max_ms: Option<u64>,
+    upload_retries: Option<usize>,
+    upload_failures_max: Option<usize>,
+    upload_pause_secs: Option<u64>,
+    max_upload_bytes: Option<u64>,
+    verify_upload: Option<bool>,
+    source: Option<Source>,
+    player: Option<Vec<String>>,
+    player_priority: Option<Vec<String>>,
+    clear_delay_ms: Option<u64>,
+    min_track_secs: Option<u64>,
+    listener_restart_max: Option<usize>,
+    update_interval_ms: Option<u64>,
+    art_wait_ms: Option<u64>,
+    position_poll_secs: Option<u64>,
+    app_id: Option<String>,
+    search_url: Option<String>,
+    search_query_format: Option<String>,
+    clean_titles: Option<bool>,
+    split_title_on: Option<String>,
+    strip_title_patterns: Option<Vec<String>>,
+    search_raw_title: Option<bool>,
+    artist_separator: Option<String>,
+    listen_button_text: Option<String>,
+    activity_type: Option<String>,
+    details_format: Option<String>,
+    state_format: Option<String>,
+    paused_state_format: Option<String>,
+    layout: Option<Layout>,
+    large_image_text: Option<String>,
+    small_image: Option<String>,
+    small_image_text: Option<String>,
+    party_id: Option<String>,
+    party_size: Option<u16>,
+    party_max: Option<u16>,
+    default_cover: Option<String>,
+    static_large_image: Option<String>,
+    idle_details: Option<String>,
+    idle_state: Option<String>,
+    live_text: Option<String>,
+    artist_label: Option<String>,
+    album_label: Option<String>,
+    hide_listen_button: Option<bool>,
+    hide_repository_button: Option<bool>,
+    no_buttons: Option<bool>,
+    button: Option<Vec<String>>,
+    hide_on_pause: Option<bool>,
+    skip_resizing: Option<bool>,
+    size: Option<String>,
+    resize_filter: Option<ResizeFilter>,
+    cover_format: Option<CoverFormat>,
+    image_host: Option<Host>,
+    imgur_client_id: Option<String>,
+    serve_local: Option<SocketAddr>,
+    art_fallback: Option<ArtFallback>,
+    trusted_art_hosts: Option<Vec<String>>,
+    rehost_untrusted_art: Option<bool>,
+    cover_only_local: Option<bool>,
+    temp_dir: Option<PathBuf>,
+    lastfm_key: Option<String>,
+    lastfm_secret: Option<String>,
+    listenbrainz_token: Option<String>,
+    history_file: Option<PathBuf>,
+    now_playing_file: Option<PathBuf>,
+    now_playing_format: Option<String>,
+    control_socket: Option<PathBuf>,
+    on_change: Option<String>,
+    #[cfg(feature = "notify")]
+    notify: Option<bool>,
+    #[cfg(feature = "embedded-art")]
+    extract_embedded_art: Option<bool>,
+    #[cfg(feature = "metrics")]
+    metrics_addr: Option<SocketAddr>,
+    no_history: Option<bool>,
+    cover_cache_size: Option<usize>,
+    cover_cache_ttl: Option<u64>,
+}
+
+/// `$XDG_CONFIG_HOME/music_presence/config.toml`
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("music_presence").join("config.toml"))
+}
+
+/// Reads the config file at `path`, or at [`default_path`] if `None`.
+/// Returns `Ok(None)` if there is no file at the default location.
+pub fn load(path: Option<&Path>) -> Result<Option<(PathBuf, Config)>, Box<dyn Error>> {
+    let (path, explicit) = match path {
+        Some(path) => (path.to_path_buf(), true),
+        None => match default_path() {
+            Some(path) => (path, false),
+            None => return Ok(None),
+        },
+    };
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound && !explicit => return Ok(None),
+        Err(e) => return Err(format!("{}: {e}", path.display()).into()),
+    };
+    let config =
+        toml::from_str(&content).map_err(|e| format!("{}: {e}", path.display()))?;
+    Ok(Some((path, config)))
+}
+
+/// Notifies `sender` whenever the config file at `path`, or at [`default_path`]
+/// if `None`, changes. Its directory is watched, as editors tend to replace the
+/// file instead of writing to it. The watcher stops when dropped.
+pub fn watch(
+    path: Option<&Path>,
+    sender: UnboundedSender<()>,
+) -> notify::Result<Option<RecommendedWatcher>> {
+    let Some(path) = path.map(Path::to_path_buf).or_else(default_path) else {
+        return Ok(None);
+    };
+    let dir = match path.parent() {
+        Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+        Some(dir) => dir,
+        None => return Ok(None),
+    };
+    if !dir.is_dir() {
+        debug!(
+            "Not watching the config file, {} does not exist",
+            dir.display()
+        );
+        return Ok(None);
+    }
+    let name = path.file_name().map(ToOwned::to_owned);
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<Event>| {
+            let changed = event.is_ok_and(|event| {
+                !matches!(event.kind, EventKind::Access(_))
+                    && event
+                        .paths
+                        .iter()
+                        .any(|changed| changed.file_name() == name.as_deref())
+            });
+            if changed {
+                let _ = sender.send(());
+            }
+        })?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    debug!("Watching config file {}", path.display());
+    Ok(Some(watcher))
+}
+
+/// Parses the contents of a config file
+impl FromStr for Config {
+    type Err = toml::de::Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        toml::from_str(text)
+    }
+}
+
+impl Config {
+    /// Sets the option `key`, named like in the config file, to `value`. Fails if
+    /// there is no such option or `value` has the wrong type, as in a config file.
+    ///
+    /// ```
+    /// use music_presence::Config;
+    ///
+    /// let config = Config::default()
+    ///     .set("details_format", "{title}")?
+    ///     .set("player", vec!["spotify", "vlc"])?
+    ///     .set("hide_repository_button", true)?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn set(
+        self,
+        key: &str,
+        value: impl Into<toml::Value>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut table = toml::Table::try_from(self)?;
+        table.insert(key.to_string(), value.into());
+        Ok(table.try_into()?)
+    }
+
+    /// Overwrites every field of `app` that was not explicitly passed on the
+    /// command line with the value from the config file.
+    pub(crate) fn apply(
+        self,
+        app: &mut App,
+        matches: &ArgMatches,
+    ) -> Result<(), Box<dyn Error>> {
+        // Environment variables count as explicitly passed, too
+        let from_cli = |id: &str| {
+            matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        };
+
+        macro_rules! apply {
+            ($($field:ident),* $(,)?) => {$(
+                if let Some(value) = self.$field {
+                    if !from_cli(stringify!($field)) {
+                        app.$field = value;
+                    }
+                }
+            )*};
+        }
+        // Same as `apply!`, for options without default value
+        macro_rules! apply_optional {
+            ($($field:ident),* $(,)?) => {$(
+                if let Some(value) = self.$field {
+                    if !from_cli(stringify!($field)) {
+                        app.$field = Some(value);
+                    }
+                }
+            )*};
+        }
+        apply!(
+            retries,
+            connect_timeout,
+            retry_base_ms,
+            retry_max_ms,
+            upload_retries,
+            upload_failures_max,
+            upload_pause_secs,
+            verify_upload,
+            source,
+            player,
+            player_priority,
+            clear_delay_ms,
+            min_track_secs,
+            listener_restart_max,
+            update_interval_ms,
+            art_wait_ms,
+            position_poll_secs,
+            listen_button_text,
+            live_text,
+            artist_label,
+            album_label,
+            clean_titles,
+            search_raw_title,
+            artist_separator,
+            layout,
+            hide_listen_button,
+            hide_repository_button,
+            no_buttons,
+            hide_on_pause,
+            skip_resizing,
+            resize_filter,
+            cover_format,
+            image_host,
+            trusted_art_hosts,
+            rehost_untrusted_art,
+            cover_only_local,
+            cover_cache_size,
+            cover_cache_ttl,
+            no_history,
+            now_playing_format,
+        );
+        apply_optional!(
+            details_format,
+            state_format,
+            paused_state_format,
+            search_query_format,
+            split_title_on,
+            large_image_text,
+            small_image,
+            small_image_text,
+            party_id,
+            party_size,
+            party_max,
+            default_cover,
+            static_large_image,
+            idle_details,
+            idle_state,
+            imgur_client_id,
+            serve_local,
+            art_fallback,
+            max_upload_bytes,
+            lastfm_key,
+            lastfm_secret,
+            listenbrainz_token,
+            history_file,
+            now_playing_file,
+            control_socket,
+            on_change,
+            temp_dir,
+        );
+        #[cfg(feature = "notify")]
+        apply!(notify);
+        #[cfg(feature = "embedded-art")]
+        apply!(extract_embedded_art);
+        #[cfg(feature = "metrics")]
+        apply_optional!(metrics_addr);
+        if let Some(id) = self.app_id.filter(|_| !from_cli("app_id")) {
+            app.app_id = parse_app_id(&id)?;
+        }
+        if let Some(url) = self.search_url.filter(|_| !from_cli("search_url")) {
+            app.search_url = parse_search_url(&url)?;
+        }
+        if let Some(patterns) = self
+            .strip_title_patterns
+            .filter(|_| !from_cli("strip_title_patterns"))
+        {
+            app.strip_title_patterns = patterns
+                .iter()
+                .map(|pattern| parse_regex(pattern))
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(buttons) = self.button.filter(|_| !from_cli("button")) {
+            app.button = buttons
+                .iter()
+                .map(|button| parse_button(button))
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(kind) = self.activity_type.filter(|_| !from_cli("activity_type")) {
+            app.activity_type = parse_activity_type(&kind).ok_or(format!(
+                "invalid activity type `{kind}`, expected listening, playing, \
+                watching or competing"
+            ))?;
+        }
+        if let Some(size) = self.size.filter(|_| !from_cli("size")) {
+            app.size = parse_size(&size).ok_or(format!(
+                "invalid size `{size}`, expected {{width}}x{{height}}, {{size}} or \
+                {{percent}}%"
+            ))?;
+        }
+        // Like `requires` on the command line, which doesn't see the config file
+        if app.party_size.is_some() != app.party_max.is_some() {
+            return Err("party_size and party_max have to be set together".into());
+        }
+        // Like `conflicts_with` on the command line
+        if app.rehost_untrusted_art && app.cover_only_local {
+            return Err(
+                "rehost_untrusted_art and cover_only_local can't be set together".into(),
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    error::Error,
+    fs, io,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use log::{debug, info, warn};
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc::UnboundedSender, oneshot},
+};
+
+/// Commands accepted on the control socket, one per line
+pub enum Command {
+    /// Reply with the current track
+    Status,
+    /// Remove the activity until the next track update
+    Clear,
+    /// Send the current activity again
+    Refresh,
+    /// Pause the player of the current track
+    Pause,
+    /// Resume the player of the current track
+    Resume,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "status" => Ok(Command::Status),
+            "clear" => Ok(Command::Clear),
+            "refresh" => Ok(Command::Refresh),
+            "pause" => Ok(Command::Pause),
+            "resume" => Ok(Command::Resume),
+            _ => Err(format!("unknown command `{s}`")),
+        }
+    }
+}
+
+/// A command together with the channel its JSON reply is sent back on
+pub struct Request {
+    pub command: Command,
+    pub reply: oneshot::Sender<Value>,
+}
+
+/// Unix socket accepting [`Command`]s, removed again when dropped
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    /// Listens on `path`, forwarding every command to `sender`
+    pub fn bind(
+        path: &Path,
+        sender: UnboundedSender<Request>,
+    ) -> Result<Self, Box<dyn Error>> {
+        // Left over from a previous run that didn't exit cleanly
+        if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let sender = sender.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = serve(stream, sender).await {
+                                debug!("Control socket client disconnected: {e}");
+                            }
+                        });
+                    }
+                    Err(e) => warn!("Failed to accept control socket client: {e}"),
+                }
+            }
+        });
+        info!("Listening for commands on {}", path.display());
+        Ok(ControlSocket {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Answers every line sent by the client with a line of JSON
+async fn serve(stream: UnixStream, sender: UnboundedSender<Request>) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let reply = match line.trim().parse() {
+            Ok(command) => {
+                let (reply, response) = oneshot::channel();
+                let _ = sender.send(Request { command, reply });
+                response
+                    .await
+                    .unwrap_or_else(|_| json!({ "error": "shutting down" }))
+            }
+            Err(e) => json!({ "error": e }),
+        };
+        writer.write_all(format!("{reply}\n").as_bytes()).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,204 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    io::ErrorKind,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    url: String,
+    /// Seconds since the epoch
+    uploaded: u64,
+}
+
+/// Maps the content hash of (resized) covers to the url they were uploaded to.
+/// The cache is persisted to [`path`] whenever a new entry is added.
+pub struct CoverCache {
+    /// Where the cache is persisted, in memory only if `None`
+    path: Option<PathBuf>,
+    entries: HashMap<String, Entry>,
+    /// Hashes in insertion order, so the oldest entry can be evicted first
+    order: VecDeque<String>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+/// `$XDG_CACHE_HOME/music_presence/covers.json`
+pub fn path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("music_presence").join("covers.json"))
+}
+
+impl CoverCache {
+    /// Loads the cache from [`path`], dropping expired entries
+    pub fn load(capacity: usize, ttl: Duration) -> Self {
+        Self::load_from(path(), capacity, ttl)
+    }
+
+    /// Loads the cache from `path` instead of the default one
+    pub fn load_from(path: Option<PathBuf>, capacity: usize, ttl: Duration) -> Self {
+        let mut cache = CoverCache {
+            path,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+            ttl,
+        };
+        let Some(path) = cache.path.clone() else {
+            return cache;
+        };
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return cache,
+            Err(e) => {
+                warn!("Failed to read cover cache {}: {e}", path.display());
+                return cache;
+            }
+        };
+        let entries: HashMap<String, Entry> = match serde_json::from_str(&content) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Ignoring corrupt cover cache {}: {e}", path.display());
+                return cache;
+            }
+        };
+
+        let mut entries: Vec<_> = entries
+            .into_iter()
+            .filter(|(_, entry)| !cache.is_expired(entry))
+            .collect();
+        entries.sort_by_key(|(_, entry)| entry.uploaded);
+        for (hash, entry) in entries {
+            cache.order.push_back(hash.clone());
+            cache.entries.insert(hash, entry);
+        }
+        cache.evict();
+        info!("Loaded {} cached cover urls", cache.entries.len());
+        cache
+    }
+
+    /// Writes the cache to where it was loaded from, logging failures
+    pub fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let result = serde_json::to_string(&self.entries)
+            .map_err(|e| e.to_string())
+            .and_then(|json| {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+                }
+                fs::write(path, json).map_err(|e| e.to_string())
+            });
+        if let Err(e) = result {
+            warn!("Failed to save cover cache to {}: {e}", path.display());
+        }
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&str> {
+        self.entries
+            .get(hash)
+            .filter(|entry| !self.is_expired(entry))
+            .map(|entry| entry.url.as_str())
+    }
+
+    pub fn insert(&mut self, hash: String, url: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        let entry = Entry {
+            url,
+            uploaded: now(),
+        };
+        // Re-inserted hashes move to the back of the queue
+        self.order.retain(|h| *h != hash);
+        self.order.push_back(hash.clone());
+        self.entries.insert(hash, entry);
+        self.evict();
+    }
+
+    /// Removes expired entries and the oldest ones exceeding the capacity
+    fn evict(&mut self) {
+        let expired: Vec<_> = self
+            .order
+            .iter()
+            .filter(|hash| self.is_expired(&self.entries[*hash]))
+            .cloned()
+            .collect();
+        for hash in expired {
+            self.entries.remove(&hash);
+        }
+        self.order.retain(|hash| self.entries.contains_key(hash));
+
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    fn is_expired(&self, entry: &Entry) -> bool {
+        now().saturating_sub(entry.uploaded) >= self.ttl.as_secs()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// How many covers [`RecentCovers`] remembers
+const RECENT_CAPACITY: usize = 16;
+
+/// Maps the content hash of covers as read, before resizing, to the url they
+/// ended up at. Unlike [`CoverCache`], this lives in memory only and works even
+/// with `--cover-cache-size 0`, skipping the resize of covers seen recently.
+pub struct RecentCovers {
+    /// Least recently used first
+    entries: VecDeque<(String, String, SystemTime)>,
+    ttl: Duration,
+}
+
+impl RecentCovers {
+    pub fn new(ttl: Duration) -> Self {
+        RecentCovers {
+            entries: VecDeque::new(),
+            ttl,
+        }
+    }
+
+    /// The url of the cover with `hash`, marking it as recently used
+    pub fn get(&mut self, hash: &str) -> Option<String> {
+        let index = self.entries.iter().position(|(h, _, _)| h == hash)?;
+        let entry = self.entries.remove(index)?;
+        if entry.2.elapsed().unwrap_or_default() >= self.ttl {
+            return None;
+        }
+        let url = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(url)
+    }
+
+    pub fn insert(&mut self, hash: String, url: String) {
+        self.entries.retain(|(h, _, _)| *h != hash);
+        self.entries.push_back((hash, url, SystemTime::now()));
+        if self.entries.len() > RECENT_CAPACITY {
+            self.entries.pop_front();
+        }
+    }
+}
+
+/// Hex encoded SHA-256 of `bytes`, which is stable across runs
+pub fn hash(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
@@ -0,0 +1,64 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    Router,
+    extract::{Path, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use log::{error, info};
+use tokio::net::TcpListener;
+
+/// Content hash -> (mime type, image)
+type Covers = Arc<Mutex<HashMap<String, (&'static str, Vec<u8>)>>>;
+
+/// Serves the covers registered during this session over http
+#[derive(Clone)]
+pub struct CoverServer {
+    addr: SocketAddr,
+    covers: Covers,
+}
+
+impl CoverServer {
+    pub async fn start(addr: SocketAddr) -> Result<Self, Box<dyn Error>> {
+        let listener = TcpListener::bind(addr).await?;
+        let covers = Covers::default();
+        let router = Router::new()
+            .route("/cover/{hash}", get(serve_cover))
+            .with_state(covers.clone());
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, router).await {
+                error!("Cover server stopped: {e}");
+            }
+        });
+        info!("Serving covers on http://{addr}");
+        Ok(CoverServer { addr, covers })
+    }
+
+    /// Makes `bytes` available under `hash` and returns their url
+    pub fn register(&self, hash: &str, bytes: Vec<u8>) -> String {
+        let mime = image::guess_format(&bytes)
+            .map(|format| format.to_mime_type())
+            .unwrap_or("application/octet-stream");
+        self.covers
+            .lock()
+            .unwrap()
+            .insert(hash.to_string(), (mime, bytes));
+        format!("http://{}/cover/{hash}", self.addr)
+    }
+}
+
+async fn serve_cover(State(covers): State<Covers>, Path(hash): Path<String>) -> Response {
+    match covers.lock().unwrap().get(&hash) {
+        Some((mime, bytes)) => {
+            ([(header::CONTENT_TYPE, *mime)], bytes.clone()).into_response()
+        }
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
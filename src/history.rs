@@ -0,0 +1,31 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use serde_json::json;
+
+use crate::track_info::TrackInfo;
+
+/// `$XDG_DATA_HOME/music_presence/history.jsonl`
+pub fn default_path() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("music_presence").join("history.jsonl"))
+}
+
+/// Appends `track` as a line of JSON to the file at `path`
+pub fn append(path: &Path, track: &TrackInfo) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let line = json!({
+        "title": track.title,
+        "artist": track.artist,
+        "album": track.album,
+        "player": track.player,
+        // Seconds since the epoch
+        "start": (track.start - track.position / 1000) / 1000,
+    });
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{line}")
+}
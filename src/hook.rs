@@ -0,0 +1,52 @@
+use log::{debug, warn};
+use tokio::{process::Command, sync::mpsc::UnboundedReceiver};
+
+use crate::{TrackUpdate, track_info::TrackInfo};
+
+/// Runs `command` with `sh` for every new track received on `updates`
+pub async fn run(command: String, mut updates: UnboundedReceiver<TrackUpdate>) {
+    let mut last: Option<TrackInfo> = None;
+
+    while let Some(update) = updates.recv().await {
+        let TrackUpdate::New(track) = update else {
+            continue;
+        };
+        // Like notifications, pausing and resuming doesn't count as a change
+        if last.as_ref() == Some(&track) {
+            continue;
+        }
+        spawn(&command, &track);
+        last = Some(track);
+    }
+}
+
+/// Starts `command` in the background, so a slow command doesn't hold up the
+/// next track
+fn spawn(command: &str, track: &TrackInfo) {
+    let child = Command::new("sh")
+        .args(["-c", command])
+        .env("MP_TITLE", &track.title)
+        .env("MP_ARTIST", &track.artist)
+        .env("MP_ALBUM", &track.album)
+        .env("MP_PLAYER", &track.player)
+        .env("MP_ART_URL", &track.art_url)
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("Failed to run --on-change command: {e}");
+            return;
+        }
+    };
+    debug!("Running --on-change command for {}", track.title);
+    // Waiting reaps the process once it exits
+    tokio::spawn(async move {
+        match child.wait().await {
+            Ok(status) if !status.success() => {
+                warn!("--on-change command failed with {status}")
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to wait for --on-change command: {e}"),
+        }
+    });
+}
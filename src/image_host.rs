@@ -0,0 +1,156 @@
+use std::error::Error;
+
+use clap::ValueEnum;
+use log::warn;
+use reqwest::{
+    Client, RequestBuilder, Url,
+    multipart::{Form, Part},
+};
+use serde::{Deserialize, Serialize};
+
+/// A service track covers can be uploaded to
+pub trait ImageHost: Send + Sync {
+    /// Builds the request uploading `file`
+    fn request(&self, client: &Client, file: Part) -> RequestBuilder;
+
+    /// Extracts the direct url of the uploaded image from the response body
+    fn image_url(&self, body: &str) -> Result<String, Box<dyn Error>>;
+}
+
+#[derive(Clone, Copy, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Host {
+    /// tmpfiles.org
+    Tmpfiles,
+    /// catbox.moe
+    Catbox,
+    /// 0x0.st
+    #[value(name = "0x0")]
+    #[serde(rename = "0x0")]
+    NullPointer,
+    /// imgur.com
+    Imgur,
+}
+
+impl Host {
+    pub fn uploader(self, imgur_client_id: Option<String>) -> Box<dyn ImageHost> {
+        match self {
+            Host::Tmpfiles => Box::new(Tmpfiles),
+            Host::Catbox => Box::new(Catbox),
+            Host::NullPointer => Box::new(NullPointer),
+            Host::Imgur => match imgur_client_id {
+                Some(client_id) => Box::new(Imgur { client_id }),
+                None => {
+                    warn!("No imgur client ID configured, using tmpfiles.org instead");
+                    Box::new(Tmpfiles)
+                }
+            },
+        }
+    }
+}
+
+pub struct Tmpfiles;
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct TmpfilesResponse {
+    status: String,
+    data: TmpfilesData,
+}
+
+#[derive(Deserialize)]
+struct TmpfilesData {
+    url: String,
+}
+
+impl ImageHost for Tmpfiles {
+    fn request(&self, client: &Client, file: Part) -> RequestBuilder {
+        client
+            .post("https://tmpfiles.org/api/v1/upload")
+            .multipart(Form::new().part("file", file))
+    }
+
+    fn image_url(&self, body: &str) -> Result<String, Box<dyn Error>> {
+        let page = serde_json::from_str::<TmpfilesResponse>(body)?.data.url;
+        let mut url = Url::parse(&page)?;
+        if url.host_str() != Some("tmpfiles.org") {
+            return Err(format!("Unexpected tmpfiles.org url: {page}").into());
+        }
+        // The returned url points to a preview page, not to the image itself
+        if !url.path().starts_with("/dl/") {
+            let path = format!("/dl{}", url.path());
+            url.set_path(&path);
+        }
+        // Discord only accepts https urls
+        url.set_scheme("https")
+            .map_err(|()| format!("Can't use https for {page}"))?;
+        Ok(url.into())
+    }
+}
+
+pub struct Catbox;
+
+impl ImageHost for Catbox {
+    fn request(&self, client: &Client, file: Part) -> RequestBuilder {
+        client.post("https://catbox.moe/user/api.php").multipart(
+            Form::new()
+                .text("reqtype", "fileupload")
+                .part("fileToUpload", file),
+        )
+    }
+
+    fn image_url(&self, body: &str) -> Result<String, Box<dyn Error>> {
+        plain_url(body)
+    }
+}
+
+pub struct NullPointer;
+
+impl ImageHost for NullPointer {
+    fn request(&self, client: &Client, file: Part) -> RequestBuilder {
+        client
+            .post("https://0x0.st")
+            .multipart(Form::new().part("file", file))
+    }
+
+    fn image_url(&self, body: &str) -> Result<String, Box<dyn Error>> {
+        plain_url(body)
+    }
+}
+
+pub struct Imgur {
+    client_id: String,
+}
+
+#[derive(Deserialize)]
+struct ImgurResponse {
+    data: ImgurData,
+}
+
+#[derive(Deserialize)]
+struct ImgurData {
+    link: String,
+}
+
+impl ImageHost for Imgur {
+    fn request(&self, client: &Client, file: Part) -> RequestBuilder {
+        client
+            .post("https://api.imgur.com/3/image")
+            .header("Authorization", format!("Client-ID {}", self.client_id))
+            .multipart(Form::new().part("image", file))
+    }
+
+    fn image_url(&self, body: &str) -> Result<String, Box<dyn Error>> {
+        Ok(serde_json::from_str::<ImgurResponse>(body)?.data.link)
+    }
+}
+
+/// For hosts responding with nothing but the url
+fn plain_url(body: &str) -> Result<String, Box<dyn Error>> {
+    let url = body.trim();
+    if url.starts_with("https://") {
+        Ok(url.to_string())
+    } else {
+        Err(format!("Unexpected response: {url}").into())
+    }
+}
@@ -0,0 +1,176 @@
+use std::{collections::BTreeMap, error::Error, fs, path::PathBuf, time::Duration};
+
+use log::{info, warn};
+use md5::{Digest, Md5};
+use serde_json::Value;
+
+use crate::{
+    scrobbler::{Scrobble, ScrobbleError, ScrobbleService},
+    track_info::TrackInfo,
+};
+
+const API_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+/// Error code for tokens the user has not authorized yet
+const UNAUTHORIZED_TOKEN: i64 = 14;
+/// Error codes for the service being offline, temporarily unavailable or rate
+/// limiting, see https://www.last.fm/api/errorcodes. All others are rejections.
+const TEMPORARY_ERRORS: [i64; 3] = [11, 16, 29];
+
+pub struct Lastfm {
+    key: String,
+    secret: String,
+    session: Option<String>,
+    client: reqwest::Client,
+}
+
+/// `$XDG_CACHE_HOME/music_presence/lastfm_session`
+fn session_path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("music_presence").join("lastfm_session"))
+}
+
+impl Lastfm {
+    pub fn new(key: String, secret: String) -> Self {
+        Lastfm {
+            key,
+            secret,
+            session: None,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Signs and sends a request, returning the response body as is
+    async fn request(
+        &self,
+        method: &str,
+        params: &[(&str, &str)],
+        post: bool,
+    ) -> Result<Value, reqwest::Error> {
+        let mut params: BTreeMap<&str, &str> = params.iter().copied().collect();
+        params.insert("method", method);
+        params.insert("api_key", &self.key);
+        if let Some(session) = &self.session {
+            params.insert("sk", session);
+        }
+
+        // See https://www.last.fm/api/authspec#_8-signing-calls
+        let mut signature: String =
+            params.iter().map(|(k, v)| format!("{k}{v}")).collect();
+        signature.push_str(&self.secret);
+        let signature: String = Md5::digest(signature)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        params.insert("api_sig", &signature);
+        params.insert("format", "json");
+
+        let request = if post {
+            self.client.post(API_URL).form(&params)
+        } else {
+            self.client.get(API_URL).query(&params)
+        };
+        request.send().await?.json().await
+    }
+
+    /// Like [`Lastfm::request`], but turns Last.fm errors into `Err`
+    async fn call(
+        &self,
+        method: &str,
+        params: &[(&str, &str)],
+    ) -> Result<Value, ScrobbleError> {
+        let body = self.request(method, params, true).await?;
+        if let Some(code) = body.get("error") {
+            let error = format!("Last.fm error {code}: {}", body["message"]).into();
+            return Err(match code.as_i64() {
+                Some(code) if TEMPORARY_ERRORS.contains(&code) => {
+                    ScrobbleError::Unavailable(error)
+                }
+                _ => ScrobbleError::Rejected(error),
+            });
+        }
+        Ok(body)
+    }
+
+    /// Lets the user authorize music_presence in the browser, see
+    /// https://www.last.fm/api/desktopauth
+    async fn authenticate(&self) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let body = self.request("auth.getToken", &[], false).await?;
+        let token = body["token"]
+            .as_str()
+            .ok_or("Failed to get a Last.fm token")?;
+        warn!(
+            "Allow music_presence to scrobble to your Last.fm account: \
+            https://www.last.fm/api/auth/?api_key={}&token={token}",
+            self.key
+        );
+
+        // Tokens are valid for 60 minutes
+        for _ in 0..720 {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            let body = self
+                .request("auth.getSession", &[("token", token)], false)
+                .await?;
+            if let Some(session) = body["session"]["key"].as_str() {
+                return Ok(session.to_string());
+            }
+            if body["error"].as_i64() != Some(UNAUTHORIZED_TOKEN) {
+                return Err(format!("Last.fm error: {}", body["message"]).into());
+            }
+        }
+        Err("Last.fm authorization timed out".into())
+    }
+}
+
+impl ScrobbleService for Lastfm {
+    const NAME: &str = "Last.fm";
+    const ID: &str = "lastfm";
+
+    async fn init(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = session_path();
+        if let Some(session) = path.as_ref().and_then(|p| fs::read_to_string(p).ok()) {
+            self.session = Some(session.trim().to_string());
+            return Ok(());
+        }
+
+        let session = self.authenticate().await?;
+        info!("Authenticated with Last.fm");
+        if let Some(path) = path {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(path, &session)?;
+        }
+        self.session = Some(session);
+        Ok(())
+    }
+
+    async fn now_playing(&self, track: &TrackInfo) -> Result<(), ScrobbleError> {
+        let duration = (track.length / 1_000_000).to_string();
+        let mut params = vec![("artist", track.artist.as_str()), ("track", &track.title)];
+        if !track.album.is_empty() {
+            params.push(("album", &track.album));
+        }
+        if track.length > 0 {
+            params.push(("duration", &duration));
+        }
+        self.call("track.updateNowPlaying", &params).await?;
+        Ok(())
+    }
+
+    async fn scrobble(&self, scrobble: &Scrobble) -> Result<(), ScrobbleError> {
+        let timestamp = scrobble.timestamp.to_string();
+        let duration = scrobble.duration.to_string();
+        let mut params = vec![
+            ("artist", scrobble.artist.as_str()),
+            ("track", &scrobble.title),
+            ("timestamp", &timestamp),
+        ];
+        if !scrobble.album.is_empty() {
+            params.push(("album", &scrobble.album));
+        }
+        if scrobble.duration > 0 {
+            params.push(("duration", &duration));
+        }
+        self.call("track.scrobble", &params).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,1891 @@
+//! Discord presence for MPRIS players, or any other source of track metadata.
+//!
+//! [`PresenceService`] shows [`TrackInfo`]s as Discord activity, the
+//! `music_presence` binary is built on top of it.
+
+mod activity;
+mod activity_type_parser;
+mod art_fallback;
+mod config;
+mod control;
+mod cover_cache;
+mod cover_server;
+mod history;
+mod hook;
+mod image_host;
+mod lastfm;
+mod listenbrainz;
+mod media_listener;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "dbus")]
+mod mpris;
+#[cfg(feature = "notify")]
+mod notification;
+mod resize;
+mod scrobbler;
+mod serve;
+mod size_parser;
+#[cfg(feature = "systemd")]
+mod systemd;
+mod track_info;
+
+use std::{
+    error::Error,
+    hash::{BuildHasher, Hasher, RandomState},
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use activity_type_parser::ActivityTypeParser;
+use art_fallback::{ArtFallback, CoverLookup};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use control::Command;
+use cover_cache::{CoverCache, RecentCovers};
+use cover_server::CoverServer;
+use discord_rich_presence::{
+    DiscordIpc, DiscordIpcClient,
+    activity::{Activity, ActivityType},
+};
+use image_host::Host;
+use log::{debug, error, info, warn};
+use media_listener::{UploadTarget, Uploader};
+use regex::Regex;
+use resize::{CoverFormat, Resize, ResizeFilter};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use size_parser::{Size, SizeParser};
+use tokio::{
+    io::AsyncBufRead,
+    sync::mpsc::{self, UnboundedReceiver},
+    task::JoinHandle,
+    time::{Instant, Interval, MissedTickBehavior, interval_at, sleep_until},
+};
+
+pub use activity::{ActivityRenderer, RenderedActivity};
+pub use config::Config;
+pub use track_info::{ArtKind, LoopStatus, PlaybackStatus, TrackInfo};
+
+const APPLICATION_ID: &str = "1210361074247802940";
+const RECONNECT_DELAY_MIN: Duration = Duration::from_secs(2);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(120);
+/// Microseconds the position reported by the player may differ from the shown one
+const POSITION_DRIFT_MAX: i64 = 2_000_000;
+/// Discord rejects activities with more buttons
+const MAX_BUTTONS: usize = 2;
+/// How long the config file has to stay unchanged before it is reloaded, as editors
+/// tend to write files in several steps
+const CONFIG_DEBOUNCE: Duration = Duration::from_millis(500);
+/// How long to wait for pending scrobbles when exiting
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Trailing segments like `(Official Music Video)` or `[HD]`
+const TITLE_PATTERNS: [&str; 2] = [
+    r"(?i)\s*[(\[][^)\]]*\b(official|video|audio|lyrics?|visuali[sz]er)\b[^)\]]*[)\]]\s*$",
+    r"(?i)\s*[(\[](hd|hq|4k|mv|m/v)[)\]]\s*$",
+];
+const SEARCH_URL: &str = "https://yewtu.be/search?q={query}&type=video";
+const DESCRIPTION: &str = "\n\nDiscord presence for ravachol/kew, or any MPRIS compatible music player.\n
+Note that activity buttons might not be visible to the user who sets the activity, but they are to everyone else.
+This is a Discord issue, see https://github.com/Mastermindzh/tidal-hifi/issues/429#issuecomment-2504798129.";
+
+#[derive(Parser)]
+#[command(version, author, about = DESCRIPTION)]
+struct App {
+    #[arg(
+        long,
+        help = "only log activities instead of sending them to Discord, and skip cover uploads"
+    )]
+    dry_run: bool,
+
+    #[arg(
+        short,
+        long,
+        default_value_t = 3,
+        help = "how often to retry if we get an ipc error"
+    )]
+    retries: usize,
+
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "seconds to wait for Discord at startup before giving up, 0 to wait forever",
+        long_help = "seconds to wait for Discord at startup before giving up, 0 to wait \
+            forever\nUntil Discord is available, the current track is kept and shown as \
+            soon as a connection is made"
+    )]
+    connect_timeout: u64,
+
+    #[arg(
+        long,
+        default_value_t = 1000,
+        help = "milliseconds to wait before the first retry, doubled on every further retry"
+    )]
+    retry_base_ms: u64,
+
+    #[arg(
+        long,
+        default_value_t = 30000,
+        help = "maximum milliseconds to wait between retries"
+    )]
+    retry_max_ms: u64,
+
+    #[arg(
+        long,
+        default_value_t = 3,
+        help = "how often to retry uploading a cover if the image host is unavailable",
+        long_help = "how often to retry uploading a cover if the image host is \
+            unavailable\nWaits as configured by --retry-base-ms and --retry-max-ms. \
+            Covers the image host refuses, e.g. for being too large, aren't retried"
+    )]
+    upload_retries: usize,
+
+    #[arg(
+        long,
+        default_value_t = 5,
+        help = "failed uploads in a row after which uploading pauses, 0 to never pause",
+        long_help = "failed uploads in a row after which uploading pauses, 0 to never \
+            pause\nWhile paused, tracks are shown with the default cover instead of \
+            trying to upload theirs. Uploading resumes after --upload-pause-secs, and \
+            the count starts over once an upload succeeds"
+    )]
+    upload_failures_max: usize,
+
+    #[arg(
+        long,
+        default_value_t = 300,
+        help = "seconds to pause uploading after --upload-failures-max failed uploads"
+    )]
+    upload_pause_secs: u64,
+
+    #[arg(
+        long,
+        help = "maximum size of uploaded covers in bytes",
+        long_help = "maximum size of uploaded covers in bytes\nLarger covers are \
+            re-encoded as JPEG with lower quality and downscaled until they fit, or not \
+            shown at all if that fails"
+    )]
+    max_upload_bytes: Option<u64>,
+
+    #[arg(
+        long,
+        help = "check that uploaded covers can be fetched before showing them",
+        long_help = "check that uploaded covers can be fetched before showing them\n\
+            Broken uploads are retried up to --upload-retries times, after which the \
+            default cover is shown"
+    )]
+    verify_upload: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Source::Player,
+        help = "where track metadata comes from",
+        long_help = "where track metadata comes from\nplayer: the players in --player, \
+            through playerctl (or D-Bus)\nstdin: one JSON object per line, in the format \
+            printed by --once. An empty line or `null` clears the activity"
+    )]
+    source: Source,
+
+    #[arg(
+        short,
+        long,
+        default_value = "kew",
+        value_delimiter = ',',
+        help = "names of the music players to follow, separated by commas (see `playerctl`)",
+        long_help = "names of the music players to follow, separated by commas \
+            (see `playerctl`)\nIf multiple players are playing at once, the one listed \
+            first is shown. `auto` follows every player, preferring --player-priority"
+    )]
+    player: Vec<String>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "players preferred with `--player auto`, separated by commas",
+        long_help = "players preferred with `--player auto`, separated by commas\nOf the \
+            playing players, the first one listed is shown, or any other if none of them \
+            is playing"
+    )]
+    player_priority: Vec<String>,
+
+    #[arg(
+        long,
+        default_value_t = 1000,
+        help = "milliseconds to wait before removing the activity when no track is playing",
+        long_help = "milliseconds to wait before removing the activity when no track is \
+            playing\nSome players briefly report no metadata when switching tracks"
+    )]
+    clear_delay_ms: u64,
+
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "seconds a new track has to play before it is shown",
+        long_help = "seconds a new track has to play before it is shown\n\
+            Tracks skipped within that time never reach Discord, pausing still updates \
+            the activity right away"
+    )]
+    min_track_secs: u64,
+
+    #[arg(
+        long,
+        default_value_t = 10,
+        help = "how often to restart playerctl in a row if it exits, 0 to never restart it",
+        long_help = "how often to restart playerctl in a row if it exits, 0 to never \
+            restart it\nplayerctl exits when the D-Bus session restarts, for example. \
+            The count is reset once it reports a track again"
+    )]
+    listener_restart_max: usize,
+
+    #[arg(
+        long,
+        default_value_t = 2000,
+        help = "minimum milliseconds between two activity updates, 0 to disable",
+        long_help = "minimum milliseconds between two activity updates, 0 to disable\n\
+            Discord only accepts a few updates per 20 seconds, so updates in between \
+            are collapsed into the latest one"
+    )]
+    update_interval_ms: u64,
+
+    #[arg(
+        long,
+        default_value_t = 2000,
+        help = "milliseconds to wait for the cover of a new track, 0 to disable",
+        long_help = "milliseconds to wait for the cover of a new track, 0 to disable\n\
+            Covers that need to be uploaded first are usually ready a moment after the \
+            track started, so the activity is sent once with the cover instead of twice. \
+            If the upload takes longer, the activity is sent without it."
+    )]
+    art_wait_ms: u64,
+
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "seconds between checks of the playback position, 0 to disable",
+        long_help = "seconds between checks of the playback position, 0 to disable\n\
+            Some players don't report seeking, which leaves the time bar wrong. If the \
+            position is off by more than 2 seconds, the activity is updated"
+    )]
+    position_poll_secs: u64,
+
+    #[arg(
+        short = 'i',
+        long,
+        default_value = APPLICATION_ID,
+        value_parser = parse_app_id,
+        help = "Discord application ID",
+        long_help = "Discord application ID\nsee https://discord.com/developers/applications"
+    )]
+    app_id: String,
+
+    #[arg(
+        long,
+        default_value = SEARCH_URL,
+        value_parser = parse_search_url,
+        help = "url the \"Listen along\" button links to",
+        long_help = "url the \"Listen along\" button links to\n`{query}` is replaced \
+            with the url-encoded \"{title} {artist}\" of the track"
+    )]
+    search_url: String,
+
+    #[arg(
+        long,
+        help = "what is searched for with --search-url [default: {title} {artist}]",
+        long_help = "what is searched for with --search-url [default: {title} {artist}]\n\
+            Supports the placeholders of --details-format. The default only uses the \
+            first of multiple artists, as more tend to worsen the search results."
+    )]
+    search_query_format: Option<String>,
+
+    #[arg(
+        long,
+        default_value = "Listen along",
+        help = "text of the button linking to --search-url"
+    )]
+    listen_button_text: String,
+
+    #[arg(
+        long,
+        value_parser = ActivityTypeParser,
+        default_value = "listening",
+        help = "what Discord shows you are doing, e.g. \"Listening to\" or \"Watching\""
+    )]
+    activity_type: ActivityType,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Layout::Classic,
+        help = "which metadata goes to which line, unless set by --details-format or \
+            --state-format",
+        long_help = "which metadata goes to which line, unless set by --details-format \
+            or --state-format\nclassic: the title, then `by: {artist}, in: {album}`\n\
+            album-first: the album, then `{title} by {artist}`\n\
+            title-only: only the title"
+    )]
+    layout: Layout,
+
+    #[arg(
+        long,
+        help = "first line of the activity [default: {title}]",
+        long_help = "first line of the activity [default: {title}]\n{title}, {artist}, \
+            {album} and {player} are replaced with the track's metadata, {loop} and \
+            {shuffle} with 🔁/🔂 and 🔀 if enabled in the player. {track_number} and \
+            {track_total} are the position of the track on its album, or empty if the \
+            player doesn't report them"
+    )]
+    details_format: Option<String>,
+
+    #[arg(
+        long,
+        help = "second line of the activity [default: by: {artist}, in: {album}]",
+        long_help = "second line of the activity [default: by: {artist}, in: {album}]\n\
+            {title}, {artist}, {album} and {player} are replaced with the track's \
+            metadata, {loop} and {shuffle} with 🔁/🔂 and 🔀 if enabled in the player, \
+            {track_number} and {track_total} with the position of the track on its album. \
+            The default leaves out the album if it is empty, see --artist-label and \
+            --album-label"
+    )]
+    state_format: Option<String>,
+
+    #[arg(
+        long,
+        default_value = "by:",
+        help = "label in front of the artist in the default --state-format",
+        long_help = "label in front of the artist in the default --state-format, e.g. \
+            \"von:\"\nPass an empty string to show the artist alone"
+    )]
+    artist_label: String,
+
+    #[arg(
+        long,
+        default_value = "in:",
+        help = "label in front of the album in the default --state-format",
+        long_help = "label in front of the album in the default --state-format, e.g. \
+            \"aus:\"\nPass an empty string to show the album alone"
+    )]
+    album_label: String,
+
+    #[arg(
+        long,
+        help = "second line of the activity while the track is paused",
+        long_help = "second line of the activity while the track is paused, e.g. \
+            \"⏸ Paused\"\nSupports the placeholders of --state-format and {position}, \
+            the position the track was paused at. Replaces the \"(paused at …)\" \
+            suffix of the default."
+    )]
+    paused_state_format: Option<String>,
+
+    #[arg(
+        long,
+        help = "text shown when hovering the cover [default: album, or artist if empty]",
+        long_help = "text shown when hovering the cover [default: album, or artist if \
+            empty]\n{title}, {artist}, {album} and {player} are replaced with the \
+            track's metadata"
+    )]
+    large_image_text: Option<String>,
+
+    #[arg(
+        long,
+        help = "url of the small image shown in the corner of the cover",
+        long_help = "url of the small image shown in the corner of the cover\n\
+            Pass `player` to use the icon of the playing player, if it is one of \
+            spotify, kew or vlc"
+    )]
+    small_image: Option<String>,
+
+    #[arg(
+        long,
+        help = "text shown when hovering the small image",
+        long_help = "text shown when hovering the small image\n{title}, {artist}, \
+            {album} and {player} are replaced with the track's metadata, e.g. \
+            `on {player}`"
+    )]
+    small_image_text: Option<String>,
+
+    #[arg(
+        long,
+        help = "id of the party shown with the activity, e.g. of a listening session",
+        long_help = "id of the party shown with the activity, e.g. of a listening \
+            session\nDiscord groups activities with the same party id"
+    )]
+    party_id: Option<String>,
+
+    #[arg(
+        long,
+        requires = "party_max",
+        help = "number of listeners in the party, shown as \"(2 of 5)\" after the state",
+        long_help = "number of listeners in the party, shown as \"(2 of 5)\" after the \
+            state\nNeeds --party-max, and is capped at it"
+    )]
+    party_size: Option<u16>,
+
+    #[arg(
+        long,
+        requires = "party_size",
+        help = "maximum number of listeners in the party"
+    )]
+    party_max: Option<u16>,
+
+    #[arg(
+        long,
+        help = "url of the cover shown for tracks without one",
+        long_help = "url of the cover shown for tracks without one\nAlso used while a \
+            local cover is being uploaded or if uploading it failed. Pass `player` to \
+            use the icon of the playing player, if it is one of spotify, kew or vlc"
+    )]
+    default_cover: Option<String>,
+
+    #[arg(
+        long,
+        help = "url of the image shown instead of every cover",
+        long_help = "url of the image shown instead of every cover\nCovers are \
+            neither uploaded nor looked up then, and --default-cover is ignored"
+    )]
+    static_large_image: Option<String>,
+
+    #[cfg(feature = "embedded-art")]
+    #[arg(
+        long,
+        help = "read the cover from the tags of local files if the player reports none",
+        long_help = "read the cover from the tags of local files if the player reports \
+            none\nWorks for players reporting the file as `xesam:url`, with covers \
+            embedded in ID3, FLAC, Vorbis or MP4 tags"
+    )]
+    extract_embedded_art: bool,
+
+    #[arg(
+        long,
+        help = "first line of the activity while nothing is playing",
+        long_help = "first line of the activity while nothing is playing\nWith this or \
+            --idle-state, the activity is kept instead of removed when no track is playing"
+    )]
+    idle_details: Option<String>,
+
+    #[arg(long, help = "second line of the activity while nothing is playing")]
+    idle_state: Option<String>,
+
+    #[arg(
+        long,
+        default_value = "LIVE",
+        help = "text appended to the second line for live streams and radio",
+        long_help = "text appended to the second line for live streams and radio\n\
+            Tracks without length are considered live and show the elapsed time \
+            instead of a progress bar. Pass an empty string to append nothing"
+    )]
+    live_text: String,
+
+    #[arg(
+        long,
+        help = "remove noise like \"(Official Video)\" from titles",
+        long_help = "remove noise like \"(Official Video)\" from titles\nAlso splits \
+            \"{artist} - {title}\" titles if the player reports no artist, unless \
+            --split-title-on is given"
+    )]
+    clean_titles: bool,
+
+    #[arg(
+        long,
+        help = "split titles into artist and title on this separator if the player \
+            reports no artist",
+        long_help = "split titles into artist and title on this separator if the player \
+            reports no artist, e.g. \" - \" for \"Daft Punk - Get Lucky\"\nTitles \
+            containing the separator more than once are kept as they are. \
+            --search-raw-title still searches for the title as reported."
+    )]
+    split_title_on: Option<String>,
+
+    #[arg(
+        long,
+        value_parser = parse_regex,
+        default_values = TITLE_PATTERNS,
+        hide_default_value = true,
+        help = "regex removed from titles with --clean-titles, can be given multiple times",
+        long_help = "regex removed from titles with --clean-titles, can be given multiple \
+            times\nReplaces the default patterns, which remove trailing brackets \
+            containing words like \"official\", \"video\" or \"lyrics\""
+    )]
+    strip_title_patterns: Vec<Regex>,
+
+    #[arg(
+        long,
+        help = "use the title as reported by the player for --search-url, even with \
+            --clean-titles"
+    )]
+    search_raw_title: bool,
+
+    #[arg(
+        long,
+        default_value = ", ",
+        help = "text put between the artists of a track with multiple artists",
+        long_help = "text put between the artists of a track with multiple artists\n\
+            playerctl reports them joined with \", \" already, so they are only told \
+            apart when read through D-Bus or from `artists` on stdin"
+    )]
+    artist_separator: String,
+
+    #[arg(long, help = "hide the button linking to --search-url")]
+    hide_listen_button: bool,
+
+    #[arg(long, help = "hide the button of the music_presence github repo")]
+    hide_repository_button: bool,
+
+    #[arg(long, help = "hide all buttons")]
+    no_buttons: bool,
+
+    #[arg(
+        long,
+        value_parser = parse_button,
+        help = "additional button as `{label}|{url}`, can be given multiple times",
+        long_help = "additional button as `{label}|{url}`, can be given multiple times\n\
+            The placeholders of --details-format are replaced in both, url-encoded in \
+            the url. Discord shows at most two buttons, including the listen and \
+            repository buttons, so hide those to make room."
+    )]
+    button: Vec<CustomButton>,
+
+    #[arg(
+        long,
+        help = "remove the activity while the track is paused",
+        long_help = "remove the activity while the track is paused\nBy default, the \
+            activity stays visible and shows the position at which the track was paused"
+    )]
+    hide_on_pause: bool,
+
+    #[arg(long, help = "do not resize local track covers before uploading them")]
+    skip_resizing: bool,
+
+    #[arg(
+        long,
+        value_parser = SizeParser,
+        help = "{width}x{height} to which track covers get resized before uploading",
+        long_help = "{width}x{height} to which track covers get resized before \
+            uploading\nA single number resizes to a square, 0 for width or height \
+            keeps the aspect ratio and {percent}% scales relative to the cover. Covers \
+            are only ever made smaller",
+        default_value = "150x150"
+    )]
+    size: Size,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ResizeFilter::Triangle,
+        help = "filter used to resize track covers, `nearest` suits pixel art"
+    )]
+    resize_filter: ResizeFilter,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = CoverFormat::Jpg,
+        help = "format resized track covers are saved as"
+    )]
+    cover_format: CoverFormat,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Host::Tmpfiles,
+        help = "where to upload local track covers to",
+        long_help = "where to upload local track covers to\n\
+            tmpfiles.org deletes files after 60 minutes, so covers of long sessions \
+            may disappear. catbox.moe keeps files permanently and 0x0.st keeps them \
+            for 30 days up to a year, depending on their size. imgur.com keeps files \
+            permanently, but requires --imgur-client-id.\n\
+            Consider raising --cover-cache-ttl if you use a long-lived host."
+    )]
+    image_host: Host,
+
+    #[arg(
+        long,
+        env = "IMGUR_CLIENT_ID",
+        help = "client ID of your imgur application, used with `--image-host imgur`",
+        long_help = "client ID of your imgur application, used with `--image-host imgur`\n\
+            see https://api.imgur.com/oauth2/addclient"
+    )]
+    imgur_client_id: Option<String>,
+
+    #[arg(
+        long,
+        help = "serve covers over http on the given address instead of uploading them",
+        long_help = "serve covers over http on the given address instead of uploading \
+            them\nOnly covers of the current session are served. Discord needs to be \
+            able to reach the address, so it has to be a LAN or public IP, not localhost."
+    )]
+    serve_local: Option<SocketAddr>,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "where to look up covers of tracks without one",
+        long_help = "where to look up covers of tracks without one\n\
+            The cover is searched by artist and album, so tracks missing either are \
+            shown without a cover. Lookups are remembered until music_presence exits."
+    )]
+    art_fallback: Option<ArtFallback>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "i.scdn.co,i.ytimg.com",
+        help = "hosts whose cover urls are shown as is with --rehost-untrusted-art, \
+            separated by commas",
+        long_help = "hosts whose cover urls are shown as is with --rehost-untrusted-art, \
+            separated by commas\n\
+            Their subdomains are trusted as well. Has no effect without \
+            --rehost-untrusted-art."
+    )]
+    trusted_art_hosts: Vec<String>,
+
+    #[arg(
+        long,
+        conflicts_with = "cover_only_local",
+        help = "upload remote covers of hosts not listed in --trusted-art-hosts again",
+        long_help = "upload remote covers of hosts not listed in --trusted-art-hosts again\n\
+            By default, remote cover urls are shown as is. Discord might not be able to \
+            fetch those of some hosts, e.g. localhost, so this downloads and uploads \
+            them to the image host instead. Also upgrades http urls of trusted hosts \
+            to https."
+    )]
+    rehost_untrusted_art: bool,
+
+    #[arg(
+        long,
+        help = "only upload local covers, remote ones are always shown as is",
+        long_help = "only upload local covers, remote ones are always shown as is\n\
+            Can't be combined with --rehost-untrusted-art, so covers never reach a third \
+            party unless they are local files or embedded in the metadata. Covers with http urls, which \
+            Discord can't show, are dropped with an error."
+    )]
+    cover_only_local: bool,
+
+    #[arg(
+        long,
+        help = "directory for temporary cover files, defaults to the system's",
+        long_help = "directory for temporary cover files, defaults to the system's\n\
+            Downloaded, resized and shrunk covers are written there before uploading \
+            them, e.g. to $TMPDIR or /tmp"
+    )]
+    temp_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        default_value_t = 100,
+        help = "how many uploaded cover urls to remember, so covers aren't uploaded twice"
+    )]
+    cover_cache_size: usize,
+
+    #[arg(
+        long,
+        default_value_t = 55,
+        help = "minutes after which cached cover urls are considered expired",
+        long_help = "minutes after which cached cover urls are considered expired\n\
+            Files uploaded to tmpfiles.org are deleted after an hour"
+    )]
+    cover_cache_ttl: u64,
+
+    #[arg(
+        short,
+        long,
+        help = "path to the config file [default: $XDG_CONFIG_HOME/music_presence/config.toml]"
+    )]
+    config: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "API key of your Last.fm application, enables scrobbling",
+        long_help = "API key of your Last.fm application, enables scrobbling together \
+            with --lastfm-secret\nsee https://www.last.fm/api/account/create\n\
+            On first use, you will be asked to allow music_presence access to your \
+            account"
+    )]
+    lastfm_key: Option<String>,
+
+    #[arg(long, help = "shared secret of your Last.fm application")]
+    lastfm_secret: Option<String>,
+
+    #[arg(
+        long,
+        help = "ListenBrainz user token, enables submitting listens",
+        long_help = "ListenBrainz user token, enables submitting listens\n\
+            see https://listenbrainz.org/settings/"
+    )]
+    listenbrainz_token: Option<String>,
+
+    #[arg(
+        long,
+        help = "file to log played tracks to [default: $XDG_DATA_HOME/music_presence/history.jsonl]"
+    )]
+    history_file: Option<PathBuf>,
+
+    #[arg(long, help = "do not log played tracks")]
+    no_history: bool,
+
+    #[arg(
+        long,
+        help = "file to write the current track to, e.g. for status bars",
+        long_help = "file to write the current track to, e.g. for status bars\nIt is \
+            replaced as a whole on every track change, formatted by \
+            --now-playing-format, and emptied when nothing is playing"
+    )]
+    now_playing_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        default_value = "{artist} - {title}",
+        help = "line written to --now-playing-file",
+        long_help = "line written to --now-playing-file\nSupports the placeholders \
+            of --details-format"
+    )]
+    now_playing_format: String,
+
+    #[cfg(feature = "notify")]
+    #[arg(long, help = "show a desktop notification when the track changes")]
+    notify: bool,
+
+    #[arg(
+        long,
+        help = "shell command to run when the track changes",
+        long_help = "shell command to run when the track changes, e.g. to update a \
+            now-playing file\nThe track is passed in the environment variables \
+            MP_TITLE, MP_ARTIST, MP_ALBUM, MP_PLAYER and MP_ART_URL, the cover as \
+            reported by the player. The command runs in the background, without \
+            waiting for it to finish."
+    )]
+    on_change: Option<String>,
+
+    #[cfg(feature = "metrics")]
+    #[arg(
+        long,
+        help = "serve Prometheus metrics on http://{metrics_addr}/metrics"
+    )]
+    metrics_addr: Option<SocketAddr>,
+
+    #[arg(
+        long,
+        help = "path of a unix socket accepting commands, like `status`",
+        long_help = "path of a unix socket accepting commands, like `status`\nEvery line \
+            sent to the socket is answered with a line of JSON, `status` replies with \
+            the current track. `clear` removes the activity until the next track \
+            update, `refresh` sends it again and `pause`/`resume` control the player"
+    )]
+    control_socket: Option<PathBuf>,
+
+    #[arg(skip)]
+    track: TrackInfo,
+
+    #[arg(skip)]
+    client: Option<Box<dyn Client>>,
+
+    /// Opens `client`, only replaced by tests
+    #[arg(skip = Arc::new(connect_ipc) as Connector)]
+    connector: Connector,
+
+    /// Whether the track should currently be shown on Discord
+    #[arg(skip)]
+    shown: bool,
+
+    /// Whether the idle activity is shown instead of `track`
+    #[arg(skip)]
+    idle: bool,
+
+    /// When the activity was last sent to Discord
+    #[arg(skip)]
+    last_update: Option<Instant>,
+
+    /// Whether an update was held back by `--update-interval-ms` or `--art-wait-ms`
+    #[arg(skip)]
+    update_pending: bool,
+
+    /// Until when to wait for the cover of the current track to be uploaded
+    #[arg(skip)]
+    art_wait: Option<Instant>,
+
+    /// What was last written to `--now-playing-file`
+    #[arg(skip)]
+    now_playing: Option<String>,
+}
+
+/// Presets for the lines of the activity, see `--layout`
+#[derive(Clone, Copy, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Layout {
+    Classic,
+    AlbumFirst,
+    TitleOnly,
+}
+
+/// Where track metadata comes from, see `--source`
+#[derive(Clone, Copy, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Source {
+    Player,
+    Stdin,
+}
+
+/// Options the listener and its uploader are spawned with, changing any of them
+/// needs a restart
+#[derive(PartialEq)]
+struct ListenerOptions {
+    source: Source,
+    players: Vec<String>,
+    clear_delay_ms: u64,
+    restart_max: usize,
+    resize: Option<Resize>,
+    upload_retries: usize,
+    retry_ms: (u64, u64),
+    upload_failures_max: usize,
+    upload_pause_secs: u64,
+    max_upload_bytes: Option<u64>,
+    verify_upload: bool,
+    trusted_art_hosts: Vec<String>,
+    rehost_untrusted_art: bool,
+    cover_only_local: bool,
+    temp_dir: Option<PathBuf>,
+    static_large_image: bool,
+    embedded_art: bool,
+}
+
+/// Listens to `args.players()` in the background, sending updates to `sender`
+fn spawn_listener(
+    sender: mpsc::UnboundedSender<TrackUpdate>,
+    args: &App,
+    uploader: Uploader,
+) -> JoinHandle<()> {
+    let players = args.players();
+    let clear_delay = Duration::from_millis(args.clear_delay_ms);
+    let restart_max = args.listener_restart_max;
+    let source = args.source;
+    tokio::spawn(async move {
+        if source == Source::Stdin {
+            if let Err(e) = media_listener::read_stdin(sender, players, uploader).await {
+                error!("Failed to read tracks from stdin: {e}");
+            }
+            return;
+        }
+        #[cfg(feature = "dbus")]
+        match mpris::subscribe(sender.clone(), players.clone(), uploader.clone()).await {
+            Ok(()) => return,
+            Err(e) if !media_listener::installed() => {
+                error!("Failed to listen on D-Bus and playerctl is not installed: {e}");
+                return;
+            }
+            Err(e) => warn!("Failed to listen on D-Bus, falling back to playerctl: {e}"),
+        }
+        if let Err(e) =
+            media_listener::subscribe(sender, players, clear_delay, restart_max, uploader)
+                .await
+        {
+            error!("Failed to listen to playerctl due to critical error: {e}");
+        }
+    })
+}
+
+/// Reads the current track of the first available of `players` once, over D-Bus if
+/// playerctl is not installed
+async fn snapshot(players: &[String]) -> Result<Option<TrackInfo>, Box<dyn Error>> {
+    #[cfg(feature = "dbus")]
+    if !media_listener::installed() {
+        info!("playerctl is not installed, reading the track over D-Bus");
+        return mpris::snapshot(players).await;
+    }
+    media_listener::snapshot(players).await
+}
+
+/// Shows the tracks of a metadata source as Discord activity. Options like the
+/// control socket, scrobbling or `--min-track-secs` only have an effect with
+/// [`serve`](Self::serve), which is what the `music_presence` binary runs.
+///
+/// Driven by a custom metadata source, which sends [`TrackUpdate`]s:
+///
+/// ```no_run
+/// use music_presence::{Config, PresenceService, TrackInfo, TrackUpdate};
+/// use tokio::sync::mpsc;
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let config = Config::default().set("details_format", "{title}")?;
+/// let service = PresenceService::builder().config(config).build().await?;
+///
+/// let (sender, updates) = mpsc::unbounded_channel();
+/// let track = TrackInfo::new(
+///     "Bohemian Rhapsody",
+///     vec!["Queen".to_string()],
+///     "A Night at the Opera",
+///     "https://example.com/cover.jpg",
+///     "my_player",
+/// );
+/// sender.send(TrackUpdate::New(track))?;
+/// // Shown until the sender is dropped
+/// tokio::spawn(async move {
+///     tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+///     drop(sender);
+/// });
+/// service.run(updates).await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct PresenceService {
+    app: App,
+    uploader: Uploader,
+    /// The command line the options came from, to reload the config file with
+    matches: Option<ArgMatches>,
+}
+
+/// Builds a [`PresenceService`], starting from the defaults of the command line
+#[derive(Default)]
+pub struct PresenceServiceBuilder {
+    config: Option<Config>,
+    matches: Option<ArgMatches>,
+}
+
+impl PresenceServiceBuilder {
+    /// Options to use instead of the defaults, as read from the config file
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Options parsed from [`PresenceService::command`], with the config file they
+    /// name (or the default one) applied instead of [`config`](Self::config).
+    /// [`serve`](PresenceService::serve) reloads it when it changes.
+    pub fn matches(mut self, matches: ArgMatches) -> Self {
+        self.matches = Some(matches);
+        self
+    }
+
+    /// Fails if the config is invalid, or if `serve_local` can't be bound
+    pub async fn build(self) -> Result<PresenceService, Box<dyn Error>> {
+        let app = match &self.matches {
+            Some(matches) => App::from_matches(matches)?,
+            None => App::from_config(self.config)?,
+        };
+        let uploader = app.uploader().await?;
+        Ok(PresenceService {
+            app,
+            uploader,
+            matches: self.matches,
+        })
+    }
+}
+
+impl PresenceService {
+    pub fn builder() -> PresenceServiceBuilder {
+        PresenceServiceBuilder::default()
+    }
+
+    /// The command line options, for [`PresenceServiceBuilder::matches`]
+    pub fn command() -> clap::Command {
+        App::command()
+    }
+
+    /// The available players with their current track, if they have one
+    pub async fn players() -> Result<Vec<(String, Option<TrackInfo>)>, Box<dyn Error>> {
+        media_listener::list().await
+    }
+
+    /// Reads the current track of the configured players once, `None` if no track
+    /// is playing. With `--dry-run`, the activity it would be shown as is logged.
+    pub async fn current_track(&mut self) -> Result<Option<TrackInfo>, Box<dyn Error>> {
+        let Some(mut track) = snapshot(&self.app.players()).await? else {
+            return Ok(None);
+        };
+        self.app.prepare(&mut track);
+        self.app.track = track.clone();
+        if let Some(Err(e)) = self.app.dry_run.then(|| self.app.set_activity()) {
+            error!("Failed to render the activity: {e}");
+        }
+        Ok(Some(track))
+    }
+
+    /// Follows the configured players through playerctl (or D-Bus) in the
+    /// background and returns their updates, with local covers already uploaded
+    pub fn listen(&self) -> UnboundedReceiver<TrackUpdate> {
+        let (sx, rx) = mpsc::unbounded_channel();
+        spawn_listener(sx, &self.app, self.uploader.clone());
+        rx
+    }
+
+    /// Like [`listen`](Self::listen), but reads the tracks from `reader` in the format
+    /// of `--source stdin`, e.g. to replay a scripted sequence of tracks. The
+    /// receiver closes once `reader` ends.
+    pub fn listen_to(
+        &self,
+        reader: impl AsyncBufRead + Unpin + Send + 'static,
+    ) -> UnboundedReceiver<TrackUpdate> {
+        let (sx, rx) = mpsc::unbounded_channel();
+        let players = self.app.players();
+        let uploader = self.uploader.clone();
+        tokio::spawn(async move {
+            if let Err(e) =
+                media_listener::read_lines(reader, sx, players, uploader).await
+            {
+                error!("Failed to read tracks: {e}");
+            }
+        });
+        rx
+    }
+
+    /// Shows the tracks received on `updates` until the sender is dropped, then
+    /// clears the activity. Covers need to be https urls, unless they come from
+    /// [`listen`](Self::listen).
+    pub async fn run(mut self, mut updates: UnboundedReceiver<TrackUpdate>) {
+        let app = &mut self.app;
+        // When to try reconnecting to Discord, if it went away while a track is shown
+        let mut reconnect_at: Option<Instant> = None;
+        let mut reconnect_delay = RECONNECT_DELAY_MIN;
+
+        loop {
+            let mut update = tokio::select! {
+                update = updates.recv() => match update {
+                    Some(update) => update,
+                    None => break,
+                },
+                _ = sleep_until(app.next_update().unwrap_or_else(Instant::now)),
+                    if app.next_update().is_some() =>
+                {
+                    if let Err(e) = app.set_activity() {
+                        error!("Failed to update the activity: {e}");
+                        app.client = None;
+                    }
+                    if app.disconnected() {
+                        reconnect_at.get_or_insert_with(|| Instant::now() + reconnect_delay);
+                    }
+                    continue;
+                }
+                _ = sleep_until(reconnect_at.unwrap_or_else(Instant::now)),
+                    if reconnect_at.is_some() =>
+                {
+                    match app.set_activity() {
+                        Ok(()) => {
+                            info!("Connected to Discord");
+                            reconnect_at = None;
+                            reconnect_delay = RECONNECT_DELAY_MIN;
+                        }
+                        Err(e) => {
+                            app.client = None;
+                            reconnect_delay = (reconnect_delay * 2).min(RECONNECT_DELAY_MAX);
+                            warn!(
+                                "Failed to reconnect to Discord, retrying in {}s: {e}",
+                                reconnect_delay.as_secs()
+                            );
+                            reconnect_at = Some(Instant::now() + reconnect_delay);
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            if let TrackUpdate::New(track) | TrackUpdate::ImageUploaded { track, .. } =
+                &mut update
+            {
+                app.prepare(track);
+            }
+            app.handle_retrying(update).await;
+            if app.disconnected() {
+                reconnect_at.get_or_insert_with(|| Instant::now() + reconnect_delay);
+            } else {
+                reconnect_at = None;
+                reconnect_delay = RECONNECT_DELAY_MIN;
+            }
+        }
+
+        if let Err(e) = app.clear_activity() {
+            warn!("Failed to clear the activity: {e}");
+        }
+    }
+}
+
+impl App {
+    /// The defaults of the command line, with `config` applied
+    fn from_config(config: Option<Config>) -> Result<App, Box<dyn Error>> {
+        let matches = App::command().try_get_matches_from([env!("CARGO_PKG_NAME")])?;
+        let mut app = App::from_arg_matches(&matches)?;
+        if let Some(config) = config {
+            config.apply(&mut app, &matches)?;
+        }
+        Ok(app)
+    }
+
+    /// The options given on the command line, with the config file applied
+    fn from_matches(matches: &ArgMatches) -> Result<App, Box<dyn Error>> {
+        let mut app = App::from_arg_matches(matches)?;
+        let Some((path, config)) = config::load(app.config.as_deref())
+            .map_err(|e| format!("Failed to load config file: {e}"))?
+        else {
+            return Ok(app);
+        };
+        info!("Loaded config file {}", path.display());
+        config
+            .apply(&mut app, matches)
+            .map_err(|e| format!("Invalid config file {}: {e}", path.display()))?;
+        Ok(app)
+    }
+
+    /// [`handle`](Self::handle)s `update`, retrying up to `--retries` times
+    async fn handle_retrying(&mut self, update: TrackUpdate) {
+        for i in 0..self.retries {
+            if let Err(e) = self.handle(update.clone()) {
+                error!("Received an error while handling TrackUpdate: {e}");
+                if e.needs_reconnect() {
+                    self.client = None;
+                }
+                if !e.is_retryable() {
+                    warn!("Retrying won't help, {update:?} will be ignored");
+                    break;
+                }
+                if i < self.retries - 1 {
+                    let delay = backoff(self.retry_base_ms, self.retry_max_ms, i);
+                    info!("Retrying in {}ms.", delay.as_millis());
+                    tokio::time::sleep(delay).await;
+                } else {
+                    warn!("Max. retries reached! {update:?} will be ignored");
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn handle(&mut self, update: TrackUpdate) -> Result<(), PresenceError> {
+        match update {
+            TrackUpdate::New(new_track) => {
+                self.idle = false;
+                if new_track.is_paused() {
+                    if new_track != self.track {
+                        #[cfg(feature = "metrics")]
+                        metrics::TRACKS.inc();
+                        self.track = new_track;
+                        self.write_history();
+                    } else {
+                        self.track.status = new_track.status;
+                        self.track.position = new_track.position;
+                        self.track.update_modes(&new_track);
+                        self.track.update_art(&new_track);
+                    }
+                    if self.track.status == PlaybackStatus::Stopped {
+                        info!("Playback stopped, removing activity status");
+                        self.clear_activity()?;
+                    } else if self.hide_on_pause {
+                        info!("Track is paused, removing activity status");
+                        self.clear_activity()?;
+                    } else {
+                        info!("Track is paused, freezing activity status");
+                        self.set_activity()?;
+                    }
+                } else if new_track != self.track {
+                    info!(
+                        title = new_track.title.as_str(),
+                        artist = new_track.artist.as_str();
+                        "Playing {} by {}", new_track.title, new_track.artist
+                    );
+                    #[cfg(feature = "metrics")]
+                    metrics::TRACKS.inc();
+                    self.art_wait = (self.art_wait_ms > 0
+                        && new_track.art_kind.needs_upload())
+                    .then(|| Instant::now() + Duration::from_millis(self.art_wait_ms));
+                    self.track = new_track;
+                    self.write_history();
+                    self.set_activity()?;
+                } else {
+                    if self.track.is_paused() {
+                        info!("Track got unpaused, restarting activity");
+                    }
+                    self.track.status = PlaybackStatus::Playing;
+                    self.track.update_art(&new_track);
+                    self.track.update_modes(&new_track);
+                    if new_track.position > 0 {
+                        self.track.start = new_track.start;
+                    }
+                    self.track.position = new_track.position;
+                    self.set_activity()?;
+                }
+                match self.track.status {
+                    PlaybackStatus::Stopped => self.write_now_playing(String::new()),
+                    _ => self
+                        .write_now_playing(self.track.render(&self.now_playing_format)),
+                }
+            }
+            TrackUpdate::ImageUploaded { track, url } => {
+                // Uploads finishing late must not show their cover on the next track
+                if track != self.track {
+                    debug!(
+                        "Dropping the cover of {}, it is no longer shown",
+                        track.title
+                    );
+                    return Ok(());
+                }
+                info!("Done uploading the cover image");
+                self.track.art_url = url;
+                self.art_wait = None;
+                let hidden = match self.track.status {
+                    PlaybackStatus::Playing => false,
+                    PlaybackStatus::Paused => self.hide_on_pause,
+                    PlaybackStatus::Stopped => true,
+                };
+                if !hidden {
+                    self.set_activity()?;
+                }
+            }
+            TrackUpdate::None => {
+                #[cfg(feature = "metrics")]
+                metrics::CLEARS.inc();
+                info!("No more tracks are playing");
+                self.write_now_playing(String::new());
+                if self.idle_details.is_some() || self.idle_state.is_some() {
+                    self.idle = true;
+                    self.set_activity()?;
+                } else {
+                    self.clear_activity()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Executes a command received on the control socket
+    async fn control(&mut self, command: Command) -> Value {
+        let result = match command {
+            Command::Status => {
+                let mut status = self.track.to_json();
+                status["shown"] = self.shown.into();
+                return status;
+            }
+            Command::Clear => self.clear_activity().map_err(Into::into),
+            Command::Refresh if self.track.title.is_empty() => Err("no track".into()),
+            Command::Refresh => self
+                .set_activity()
+                .inspect_err(|_| self.client = None)
+                .map_err(Into::into),
+            Command::Pause => self.playerctl("pause").await.map(|_| ()),
+            Command::Resume => self.playerctl("play").await.map(|_| ()),
+        };
+        match result {
+            Ok(()) => json!({ "ok": true }),
+            Err(e) => json!({ "ok": false, "error": e.to_string() }),
+        }
+    }
+
+    /// Runs `playerctl <command>` for the player of the current track and returns
+    /// its output
+    async fn playerctl(&self, command: &str) -> Result<String, Box<dyn Error>> {
+        if self.track.player.is_empty() {
+            return Err("no track".into());
+        }
+        let output = tokio::process::Command::new("playerctl")
+            .args(["--player", &self.track.player, command])
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(
+                format!("playerctl {command} failed with {}", output.status).into()
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Asks the player for the position of the current track, returns whether it was
+    /// off by more than [`POSITION_DRIFT_MAX`] and got corrected
+    async fn poll_position(&mut self) -> Result<bool, Box<dyn Error>> {
+        let seconds: f64 = self.playerctl("position").await?.trim().parse()?;
+        let position = (seconds * 1_000_000.0) as i64;
+        if (position - self.track.current_position()).abs() <= POSITION_DRIFT_MAX {
+            return Ok(false);
+        }
+        debug!(
+            "Position drifted from {} to {}",
+            fmt_duration(self.track.current_position()),
+            fmt_duration(position)
+        );
+        self.track.seek(position);
+        Ok(true)
+    }
+
+    /// Applies `--clean-titles`, `--split-title-on` and `--artist-separator` to a
+    /// newly received track
+    fn prepare(&self, track: &mut TrackInfo) {
+        if self.clean_titles {
+            track.clean_title(&self.strip_title_patterns);
+        }
+        let separator = match &self.split_title_on {
+            Some(separator) => Some(separator.as_str()),
+            None => self.clean_titles.then_some(" - "),
+        };
+        if let Some(separator) = separator {
+            track.split_title(separator);
+        }
+        track.join_artists(&self.artist_separator);
+    }
+
+    /// Appends the current track to the history file, if enabled
+    fn write_history(&self) {
+        if self.no_history {
+            return;
+        }
+        let Some(path) = self.history_file.clone().or_else(history::default_path) else {
+            return;
+        };
+        if let Err(e) = history::append(&path, &self.track) {
+            warn!("Failed to write to history file {}: {e}", path.display());
+        }
+    }
+
+    /// Replaces the content of `--now-playing-file` with `line`, if it changed
+    fn write_now_playing(&mut self, line: String) {
+        let Some(path) = &self.now_playing_file else {
+            return;
+        };
+        if self.now_playing.as_ref() == Some(&line) {
+            return;
+        }
+        let content = match line.is_empty() {
+            true => String::new(),
+            false => format!("{line}\n"),
+        };
+        if let Err(e) = write_atomic(path, &content) {
+            warn!("Failed to write now playing file {}: {e}", path.display());
+        }
+        self.now_playing = Some(line);
+    }
+
+    fn set_activity(&mut self) -> Result<(), PresenceError> {
+        self.shown = true;
+        // Rendered from the then current track once the interval is over
+        if self
+            .update_allowed_at()
+            .is_some_and(|at| Instant::now() < at)
+        {
+            self.update_pending = true;
+            return Ok(());
+        }
+        self.update_pending = false;
+        if self.idle {
+            return self.set_idle_activity();
+        }
+
+        let rendered = self.render_activity(&self.track);
+        Self::send_activity(
+            &mut self.client,
+            &self.connector,
+            &self.app_id,
+            self.dry_run,
+            rendered.activity(),
+        )?;
+        self.last_update = Some(Instant::now());
+
+        Ok(())
+    }
+
+    /// Shows `--idle-details` and `--idle-state`, with the default cover if there is one
+    fn set_idle_activity(&mut self) -> Result<(), PresenceError> {
+        let rendered = self.render_idle();
+        Self::send_activity(
+            &mut self.client,
+            &self.connector,
+            &self.app_id,
+            self.dry_run,
+            rendered.activity(),
+        )?;
+        self.last_update = Some(Instant::now());
+
+        Ok(())
+    }
+
+    /// Sends `activity` to Discord, connecting first if there is no `client` yet
+    fn send_activity(
+        client: &mut Option<Box<dyn Client>>,
+        connector: &Connector,
+        app_id: &str,
+        dry_run: bool,
+        activity: Activity,
+    ) -> Result<(), PresenceError> {
+        if dry_run {
+            info!("Would set activity {}", serde_json::to_string(&activity)?);
+            return Ok(());
+        }
+        let c = match client {
+            Some(c) => c,
+            None => client.insert(connector(app_id).map_err(PresenceError::Connect)?),
+        };
+        c.set_activity(activity)
+    }
+
+    /// Fits `text` into the 2 to `max` characters Discord accepts for activity fields,
+    /// as it rejects the whole activity otherwise
+    fn fit_field(name: &str, text: &str, max: usize) -> String {
+        let mut fitted = truncate(text, max);
+        if fitted != text {
+            debug!("Truncated {name} to `{fitted}`");
+        }
+        while fitted.chars().count() < 2 {
+            // Zero width space
+            fitted.push('\u{200b}');
+        }
+        fitted
+    }
+
+    /// Re-reads the config file and replaces all options with the command line
+    /// arguments and the new config. Options only used at startup, like the image
+    /// host or the scrobbling credentials, keep their previous effect.
+    fn reload(&mut self, matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+        let mut fresh = App::from_arg_matches(matches)?;
+        if let Some((path, config)) = config::load(fresh.config.as_deref())? {
+            config
+                .apply(&mut fresh, matches)
+                .map_err(|e| format!("{}: {e}", path.display()))?;
+            info!("Reloaded config file {}", path.display());
+        }
+        if fresh.app_id != self.app_id {
+            // The connection is bound to the application id
+            if let Some(mut c) = self.client.take() {
+                let _ = c.close();
+            }
+        }
+        // Only the options change, not what is shown or when it was last updated
+        let now_playing = self
+            .now_playing
+            .take()
+            .filter(|_| fresh.now_playing_file == self.now_playing_file);
+        *self = App {
+            track: std::mem::take(&mut self.track),
+            client: self.client.take(),
+            shown: self.shown,
+            idle: self.idle,
+            last_update: self.last_update,
+            update_pending: self.update_pending,
+            art_wait: self.art_wait,
+            now_playing,
+            ..fresh
+        };
+        Ok(())
+    }
+
+    /// Sets up cover uploads to `--image-host`, or serving them on `--serve-local`
+    async fn uploader(&self) -> Result<Uploader, Box<dyn Error>> {
+        let cover_cache = CoverCache::load(self.cover_cache_size, self.cover_cache_ttl());
+        let target = match self.serve_local {
+            Some(addr) => UploadTarget::Local(
+                CoverServer::start(addr)
+                    .await
+                    .map_err(|e| format!("Failed to serve covers on {addr}: {e}"))?,
+            ),
+            None => UploadTarget::Host(
+                self.image_host
+                    .uploader(self.imgur_client_id.clone())
+                    .into(),
+            ),
+        };
+        Ok(Uploader {
+            resize: self.resize(),
+            retries: self.upload_retries,
+            failures_max: self.upload_failures_max,
+            pause: Duration::from_secs(self.upload_pause_secs),
+            breaker: Arc::default(),
+            retry_ms: (self.retry_base_ms, self.retry_max_ms),
+            max_bytes: self.max_upload_bytes,
+            verify: self.verify_upload,
+            art_fallback: self
+                .art_fallback
+                .map(|provider| Arc::new(CoverLookup::new(provider))),
+            trusted_art_hosts: self.trusted_art_hosts.clone(),
+            rehost_untrusted: self.rehost_untrusted_art,
+            only_local: self.cover_only_local,
+            temp_dir: self.temp_dir.clone(),
+            static_image: self.static_large_image.is_some(),
+            embedded_art: self.embedded_art(),
+            cache: Arc::new(Mutex::new(cover_cache)),
+            recent: Arc::new(Mutex::new(RecentCovers::new(self.cover_cache_ttl()))),
+            target,
+            dry_run: self.dry_run,
+        })
+    }
+
+    /// How long uploaded covers are assumed to stay available
+    fn cover_cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.cover_cache_ttl * 60)
+    }
+
+    /// Whether covers are read from the tags of local files, see
+    /// `--extract-embedded-art`
+    fn embedded_art(&self) -> bool {
+        #[cfg(feature = "embedded-art")]
+        return self.extract_embedded_art;
+        #[cfg(not(feature = "embedded-art"))]
+        false
+    }
+
+    /// How covers are resized before uploading, if at all
+    fn resize(&self) -> Option<Resize> {
+        (!self.skip_resizing).then_some(Resize {
+            size: self.size,
+            filter: self.resize_filter,
+            format: self.cover_format,
+        })
+    }
+
+    /// Ticks every `--position-poll-secs`
+    fn position_poll(&self) -> Interval {
+        let period = Duration::from_secs(self.position_poll_secs.max(1));
+        let mut poll = interval_at(Instant::now() + period, period);
+        poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        poll
+    }
+
+    fn update_interval(&self) -> Duration {
+        Duration::from_millis(self.update_interval_ms)
+    }
+
+    /// When the activity may be updated again, after `--update-interval-ms` passed
+    /// and the cover is uploaded or `--art-wait-ms` passed
+    fn update_allowed_at(&self) -> Option<Instant> {
+        let throttled = self.last_update.map(|last| last + self.update_interval());
+        throttled.max(self.art_wait)
+    }
+
+    /// When to send an update held back by `--update-interval-ms` or `--art-wait-ms`
+    fn next_update(&self) -> Option<Instant> {
+        self.update_allowed_at().filter(|_| self.update_pending)
+    }
+
+    /// Options the listener and its uploader are spawned with
+    fn listener_options(&self) -> ListenerOptions {
+        ListenerOptions {
+            source: self.source,
+            players: self.players(),
+            clear_delay_ms: self.clear_delay_ms,
+            restart_max: self.listener_restart_max,
+            resize: self.resize(),
+            upload_retries: self.upload_retries,
+            retry_ms: (self.retry_base_ms, self.retry_max_ms),
+            upload_failures_max: self.upload_failures_max,
+            upload_pause_secs: self.upload_pause_secs,
+            max_upload_bytes: self.max_upload_bytes,
+            verify_upload: self.verify_upload,
+            trusted_art_hosts: self.trusted_art_hosts.clone(),
+            rehost_untrusted_art: self.rehost_untrusted_art,
+            cover_only_local: self.cover_only_local,
+            temp_dir: self.temp_dir.clone(),
+            static_large_image: self.static_large_image.is_some(),
+            embedded_art: self.embedded_art(),
+        }
+    }
+
+    /// The players to follow, by priority. `--player auto` is turned into
+    /// `--player-priority` followed by playerctl's wildcard
+    fn players(&self) -> Vec<String> {
+        match &self.player[..] {
+            [auto] if auto == "auto" => {
+                let mut players = self.player_priority.clone();
+                players.push(media_listener::ANY_PLAYER.to_string());
+                players
+            }
+            _ => self.player.clone(),
+        }
+    }
+
+    /// Whether the track should be shown, but there is no connection to Discord
+    fn disconnected(&self) -> bool {
+        self.shown && self.client.is_none() && !self.dry_run
+    }
+
+    fn clear_activity(&mut self) -> Result<(), PresenceError> {
+        if self.dry_run && self.shown {
+            info!("Would clear activity");
+        }
+        self.shown = false;
+        self.update_pending = false;
+        self.art_wait = None;
+        if let Some(c) = self.client.as_mut() {
+            c.clear_activity()?;
+            c.close()?;
+            self.client = None;
+        }
+
+        Ok(())
+    }
+}
+
+/// Replaces the content of `path` through a temporary file next to it, so readers
+/// never see a partially written file
+fn write_atomic(path: &Path, content: &str) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(content.as_bytes())?;
+    file.persist(path)?;
+    Ok(())
+}
+
+/// Shortens `text` to at most `max` characters, ending it with `…` if it was too long
+fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(max - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// Icon of a known music player, used for `--small-image player` and
+/// `--default-cover player`
+fn player_icon(player: &str) -> Option<&'static str> {
+    // Ignore the instance suffix, like in `vlc.instance1234`
+    match player.split('.').next().unwrap_or_default() {
+        "spotify" => {
+            Some("https://www.google.com/s2/favicons?domain=open.spotify.com&sz=128")
+        }
+        "kew" => Some("https://github.com/ravachol.png"),
+        "vlc" => Some("https://www.google.com/s2/favicons?domain=videolan.org&sz=128"),
+        _ => None,
+    }
+}
+
+/// Makes sure the `--app-id` looks like a Discord snowflake, as a typo would only
+/// surface as an IPC error once connecting
+fn parse_app_id(value: &str) -> Result<String, String> {
+    if value.chars().all(|c| c.is_ascii_digit()) && (17..=20).contains(&value.len()) {
+        Ok(value.to_string())
+    } else {
+        Err("expected the 17 to 20 digit ID of a Discord application".to_string())
+    }
+}
+
+/// Makes sure the `--search-url` template contains the `{query}` placeholder
+fn parse_search_url(value: &str) -> Result<String, String> {
+    if value.contains("{query}") {
+        Ok(value.to_string())
+    } else {
+        Err("the url needs to contain a `{query}` placeholder".to_string())
+    }
+}
+
+/// A `--button`
+#[derive(Clone)]
+struct CustomButton {
+    label: String,
+    url: String,
+}
+
+/// Parses a `--button` of the form `{label}|{url}`
+fn parse_button(value: &str) -> Result<CustomButton, String> {
+    let (label, url) = value
+        .split_once('|')
+        .ok_or("expected `{label}|{url}`".to_string())?;
+    if !url.starts_with("https://") && !url.starts_with("http://") {
+        return Err("the url needs to start with https:// or http://".to_string());
+    }
+    Ok(CustomButton {
+        label: label.to_string(),
+        url: url.to_string(),
+    })
+}
+
+/// Delay before retry number `attempt` (starting at 0): `base_ms` doubled on every
+/// attempt, capped at `max_ms`, with up to 25% random jitter subtracted so that
+/// retries don't line up
+fn backoff(base_ms: u64, max_ms: u64, attempt: usize) -> Duration {
+    let delay = base_ms.saturating_mul(1 << attempt.min(32)).min(max_ms);
+    let jitter = RandomState::new().build_hasher().finish() % (delay / 4 + 1);
+    Duration::from_millis(delay - jitter)
+}
+
+/// Parses a `--strip-title-patterns` regex
+fn parse_regex(value: &str) -> Result<Regex, String> {
+    Regex::new(value).map_err(|e| e.to_string())
+}
+
+/// Formats a duration given in microseconds as `m:ss`
+fn fmt_duration(micros: i64) -> String {
+    let secs = micros / 1_000_000;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+/// The connection to Discord, a trait so tests can stand in for it
+trait Client: Send {
+    fn set_activity(&mut self, activity: Activity) -> Result<(), PresenceError>;
+    fn clear_activity(&mut self) -> Result<(), PresenceError>;
+    fn close(&mut self) -> Result<(), PresenceError>;
+}
+
+impl Client for DiscordIpcClient {
+    fn set_activity(&mut self, activity: Activity) -> Result<(), PresenceError> {
+        DiscordIpc::set_activity(self, activity).map_err(PresenceError::SetActivity)
+    }
+
+    fn clear_activity(&mut self) -> Result<(), PresenceError> {
+        DiscordIpc::clear_activity(self).map_err(PresenceError::ClearActivity)
+    }
+
+    fn close(&mut self) -> Result<(), PresenceError> {
+        DiscordIpc::close(self).map_err(PresenceError::ClearActivity)
+    }
+}
+
+/// Connects to Discord as the application with the given id
+type Connector =
+    Arc<dyn Fn(&str) -> Result<Box<dyn Client>, Box<dyn Error>> + Send + Sync>;
+
+fn connect_ipc(app_id: &str) -> Result<Box<dyn Client>, Box<dyn Error>> {
+    let mut c = DiscordIpcClient::new(app_id)?;
+    c.connect()?;
+    Ok(Box::new(c))
+}
+
+/// What failed while updating the activity
+#[derive(Debug, thiserror::Error)]
+enum PresenceError {
+    #[error("failed to connect to Discord: {0}")]
+    Connect(Box<dyn Error>),
+    #[error("failed to set the activity: {0}")]
+    SetActivity(Box<dyn Error>),
+    #[error("failed to clear the activity: {0}")]
+    ClearActivity(Box<dyn Error>),
+    #[error("failed to serialize the activity: {0}")]
+    Serialize(#[from] serde_json::Error),
+}
+
+impl PresenceError {
+    /// Whether the connection to Discord is unusable, so it has to be re-established
+    fn needs_reconnect(&self) -> bool {
+        !matches!(self, PresenceError::Serialize(_))
+    }
+
+    /// Whether trying again might succeed. An activity that can't be serialized never
+    /// will, while Discord might just be restarting
+    fn is_retryable(&self) -> bool {
+        match self {
+            PresenceError::Connect(_)
+            | PresenceError::SetActivity(_)
+            | PresenceError::ClearActivity(_) => true,
+            PresenceError::Serialize(_) => false,
+        }
+    }
+}
+
+/// What the metadata source reports to a [`PresenceService`]
+#[derive(Clone, Debug)]
+pub enum TrackUpdate {
+    /// Metadata of the current track, also sent when it got paused or seeked
+    New(TrackInfo),
+    /// The cover of `track` was uploaded to `url`, which is empty if it has none
+    ImageUploaded { track: TrackInfo, url: String },
+    /// No more tracks are playing
+    None,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discord_errors_are_retried() {
+        for error in [
+            PresenceError::Connect("not running".into()),
+            PresenceError::SetActivity("broken pipe".into()),
+            PresenceError::ClearActivity("broken pipe".into()),
+        ] {
+            assert!(error.is_retryable(), "{error}");
+            assert!(error.needs_reconnect(), "{error}");
+        }
+    }
+
+    /// Fails every activity with `error`, counting the attempts
+    struct FailingClient {
+        attempts: Arc<Mutex<usize>>,
+        error: fn() -> PresenceError,
+    }
+
+    impl Client for FailingClient {
+        fn set_activity(&mut self, _: Activity) -> Result<(), PresenceError> {
+            *self.attempts.lock().unwrap() += 1;
+            Err((self.error)())
+        }
+
+        fn clear_activity(&mut self) -> Result<(), PresenceError> {
+            Ok(())
+        }
+
+        fn close(&mut self) -> Result<(), PresenceError> {
+            Ok(())
+        }
+    }
+
+    /// Handles a new track with every attempt failing with `error`, returning how
+    /// often it was attempted and how long that took
+    async fn attempts(error: fn() -> PresenceError) -> (usize, Duration) {
+        let mut app = App::from_config(None).unwrap();
+        app.retries = 3;
+        (app.retry_base_ms, app.retry_max_ms) = (40, 40);
+        let attempts = Arc::new(Mutex::new(0));
+        let counter = attempts.clone();
+        app.connector = Arc::new(move |_| {
+            Ok(Box::new(FailingClient {
+                attempts: counter.clone(),
+                error,
+            }))
+        });
+
+        let track = TrackInfo {
+            title: "Song".to_string(),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        app.handle_retrying(TrackUpdate::New(track)).await;
+        let attempts = *attempts.lock().unwrap();
+        (attempts, started.elapsed())
+    }
+
+    #[tokio::test]
+    async fn transient_errors_are_retried_with_backoff() {
+        let (attempts, took) =
+            attempts(|| PresenceError::SetActivity("broken pipe".into())).await;
+        assert_eq!(attempts, 3);
+        // Two delays of 40ms minus up to a quarter of jitter
+        assert!(took >= Duration::from_millis(60), "{took:?}");
+    }
+
+    #[tokio::test]
+    async fn permanent_errors_are_not_retried() {
+        let (attempts, _) =
+            attempts(|| serde_json::from_str::<u8>("-1").unwrap_err().into()).await;
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn serialize_errors_are_permanent() {
+        let error = PresenceError::from(serde_json::from_str::<u8>("-1").unwrap_err());
+        assert!(!error.is_retryable());
+        assert!(!error.needs_reconnect());
+    }
+
+    fn track(title: &str) -> TrackInfo {
+        TrackInfo {
+            title: title.to_string(),
+            art_url: format!("file:///{title}.png"),
+            art_kind: ArtKind::Local,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn late_covers_are_dropped() {
+        let mut app = App::from_config(None).unwrap();
+        app.dry_run = true;
+        app.handle(TrackUpdate::New(track("First"))).unwrap();
+        app.handle(TrackUpdate::New(track("Second"))).unwrap();
+        let cover = |title: &str| TrackUpdate::ImageUploaded {
+            track: track(title),
+            url: format!("https://example.com/{title}.png"),
+        };
+
+        app.handle(cover("First")).unwrap();
+        assert_eq!(app.track.art_url, "file:///Second.png");
+        app.handle(cover("Second")).unwrap();
+        assert_eq!(app.track.art_url, "https://example.com/Second.png");
+    }
+}
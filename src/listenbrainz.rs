@@ -0,0 +1,101 @@
+use serde_json::{Value, json};
+
+use crate::{
+    scrobbler::{Scrobble, ScrobbleError, ScrobbleService},
+    track_info::TrackInfo,
+};
+
+const API_URL: &str = "https://api.listenbrainz.org/1/submit-listens";
+
+pub struct ListenBrainz {
+    token: String,
+    client: reqwest::Client,
+}
+
+impl ListenBrainz {
+    pub fn new(token: String) -> Self {
+        ListenBrainz {
+            token,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// See https://listenbrainz.readthedocs.io/en/latest/users/api/core.html
+    async fn submit(
+        &self,
+        listen_type: &str,
+        listen: Value,
+    ) -> Result<(), ScrobbleError> {
+        self.client
+            .post(API_URL)
+            .header("Authorization", format!("Token {}", self.token))
+            .json(&json!({
+                "listen_type": listen_type,
+                "payload": [listen],
+            }))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+fn track_metadata(
+    title: &str,
+    artist: &str,
+    album: &str,
+    player: &str,
+    duration: u64,
+) -> Value {
+    let mut metadata = json!({
+        "artist_name": artist,
+        "track_name": title,
+        "additional_info": {
+            "music_player": player,
+            "submission_client": "music_presence",
+            "submission_client_version": env!("CARGO_PKG_VERSION"),
+        },
+    });
+    if !album.is_empty() {
+        metadata["release_name"] = json!(album);
+    }
+    if duration > 0 {
+        metadata["additional_info"]["duration"] = json!(duration);
+    }
+    metadata
+}
+
+impl ScrobbleService for ListenBrainz {
+    const NAME: &str = "ListenBrainz";
+    const ID: &str = "listenbrainz";
+
+    async fn now_playing(&self, track: &TrackInfo) -> Result<(), ScrobbleError> {
+        let metadata = track_metadata(
+            &track.title,
+            &track.artist,
+            &track.album,
+            &track.player,
+            track.length.max(0) as u64 / 1_000_000,
+        );
+        self.submit("playing_now", json!({ "track_metadata": metadata }))
+            .await
+    }
+
+    async fn scrobble(&self, scrobble: &Scrobble) -> Result<(), ScrobbleError> {
+        let metadata = track_metadata(
+            &scrobble.title,
+            &scrobble.artist,
+            &scrobble.album,
+            &scrobble.player,
+            scrobble.duration,
+        );
+        self.submit(
+            "single",
+            json!({
+                "listened_at": scrobble.timestamp,
+                "track_metadata": metadata,
+            }),
+        )
+        .await
+    }
+}
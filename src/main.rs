@@ -1,456 +1,208 @@
-use std::{error::Error, time::Duration};
+//! The `music_presence` command line interface, which runs
+//! [`PresenceService::serve`] with the options it was started with.
 
-use clap::Parser;
-use discord_rich_presence::{
-    DiscordIpc, DiscordIpcClient,
-    activity::{Activity, ActivityType, Assets, Button, Timestamps},
-};
-use log::{error, info, warn};
-use size_parser::SizeParser;
-use tokio::sync::mpsc;
-use track_info::TrackInfo;
-use urlencoding::encode;
+mod logging;
 
-const APPLICATION_ID: &str = "1210361074247802940";
-const DESCRIPTION: &str = "\n\nDiscord presence for ravachol/kew, or any MPRIS compatible music player.\n
-Note that activity buttons might not be visible to the user who sets the activity, but they are to everyone else.
-This is a Discord issue, see https://github.com/Mastermindzh/tidal-hifi/issues/429#issuecomment-2504798129.";
+use std::{io, path::PathBuf, process::exit};
 
-#[derive(Parser)]
-#[command(version, author, about = DESCRIPTION)]
-struct App {
-    #[arg(short, long)]
-    verbose: bool,
+use clap::{ArgAction, Args, Command, FromArgMatches, Subcommand};
+use clap_complete::Shell;
+use log::{error, info};
+use logging::{LogFormat, RotatingFile, Tee};
+use music_presence::PresenceService;
 
+// Options of the binary, on top of those of the service. Neither this nor
+// `Generate` have doc comments, which would replace the description of the command.
+#[derive(Args)]
+struct Cli {
     #[arg(
         short,
         long,
-        default_value_t = 3,
-        help = "how often to retry if we get an ipc error"
+        action = ArgAction::Count,
+        help = "log more, -v for info, -vv for debug and -vvv for trace messages",
+        long_help = "log more, -v for info, -vv for debug and -vvv for trace messages\n\
+            Without it, the level is taken from RUST_LOG"
     )]
-    retries: usize,
+    verbose: u8,
 
     #[arg(
         short,
         long,
-        default_value = "kew",
-        help = "name of the music player to follow (see `playerctl`)"
+        conflicts_with = "verbose",
+        help = "only log errors",
+        long_help = "only log errors, overriding RUST_LOG"
     )]
-    player: String,
+    quiet: bool,
 
     #[arg(
-        short = 'i',
         long,
-        default_value = APPLICATION_ID,
-        help = "Discord application ID",
-        long_help = "Discord application ID\nsee https://discord.com/developers/applications"
+        help = "file to write the log to, in addition to stderr",
+        long_help = "file to write the log to, in addition to stderr\nIt is rotated \
+            once it exceeds --log-file-size, keeping --log-file-count old files next to it \
+            as {log_file}.1, {log_file}.2 and so on"
     )]
-    app_id: String,
+    log_file: Option<PathBuf>,
 
-    #[arg(long, help = "hide the button of the music_presence github repo")]
-    hide_repository_button: bool,
+    #[arg(
+        long,
+        default_value_t = 1_000_000,
+        help = "bytes after which the log file is rotated"
+    )]
+    log_file_size: u64,
 
-    #[arg(long, help = "do not resize local track covers before uploading them")]
-    skip_resizing: bool,
+    #[arg(long, default_value_t = 3, help = "how many rotated log files to keep")]
+    log_file_count: usize,
 
     #[arg(
         long,
-        value_parser = SizeParser,
-        help = "{width}x{height} to which track covers get resized before uploading",
-        default_value = "150x150"
+        value_enum,
+        default_value_t = LogFormat::Text,
+        help = "format of the log, `json` prints one object per line"
     )]
-    size: (u32, u32),
+    log_format: LogFormat,
 
-    #[arg(skip)]
-    track: TrackInfo,
+    #[arg(
+        long,
+        help = "print the current track as JSON and exit",
+        long_help = "print the current track as JSON and exit\nExits with 1 if no track \
+            is playing. Combine with --dry-run to also log the activity that would be set"
+    )]
+    once: bool,
 
-    #[arg(skip)]
-    client: Option<DiscordIpcClient>,
-}
+    #[arg(
+        long,
+        help = "print the available players with their status and track, then exit",
+        long_help = "print the available players with their status and track, then \
+            exit\nAny of the names can be passed to --player. Instances like \
+            `vlc.instance1234` are also followed as `vlc`."
+    )]
+    players_list: bool,
 
-#[tokio::main]
-async fn main() {
-    let mut args = App::parse();
+    #[command(subcommand)]
+    command: Option<Generate>,
+}
 
-    if args.verbose {
-        env_logger::builder()
-            .filter_level(log::LevelFilter::Trace)
-            .init();
-    } else {
-        env_logger::init();
-    }
+// Files for packaging, hidden from the help
+#[derive(Subcommand)]
+enum Generate {
+    /// Print the completion script for `shell`
+    #[command(hide = true)]
+    Completions { shell: Shell },
+    /// Print the man page
+    #[command(hide = true)]
+    Man,
+}
 
-    let (sx, mut rx) = mpsc::unbounded_channel();
+fn command() -> Command {
+    Cli::augment_args(PresenceService::command())
+}
 
-    let player = args.player.clone();
-    let resize = (!args.skip_resizing).then_some(args.size);
-    tokio::spawn(async move {
-        if let Err(e) = media_listener::subscribe(sx, player, resize).await {
-            error!("Failed to listen to playerctl due to critical error: {e}");
+#[tokio::main]
+async fn main() {
+    let matches = command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    match cli.command {
+        Some(Generate::Completions { shell }) => {
+            clap_complete::generate(
+                shell,
+                &mut command(),
+                env!("CARGO_PKG_NAME"),
+                &mut io::stdout(),
+            );
+            return;
         }
-    });
-
-    while let Some(update) = rx.recv().await {
-        for i in 0..args.retries {
-            if let Err(e) = args.handle(update.clone()) {
-                error!("Received an error while handling TrackUpdate: {e}");
-                args.client = None;
-                if i < args.retries - 1 {
-                    info!("Retrying in 1 second.");
-                    tokio::time::sleep(Duration::from_secs(1)).await;
-                } else {
-                    warn!("Max. retries reached! {update:?} will be ignored");
-                }
-            } else {
-                break;
+        Some(Generate::Man) => {
+            if let Err(e) = clap_mangen::Man::new(command()).render(&mut io::stdout()) {
+                eprintln!("Failed to render the man page: {e}");
+                exit(1);
             }
+            return;
         }
+        None => {}
     }
 
-    warn!("Sender dropped, exiting");
-}
+    init_logger(&cli);
 
-impl App {
-    fn handle(&mut self, update: TrackUpdate) -> Result<(), Box<dyn Error>> {
-        match update {
-            TrackUpdate::New(new_track) => {
-                if new_track.paused {
-                    if new_track != self.track {
-                        self.track = new_track;
-                    }
-                    info!("Track is paused, removing activity status");
-                    self.clear_activity()?;
-                } else if new_track != self.track {
-                    info!("Playing {} by {}", new_track.title, new_track.artist);
-                    self.track = new_track;
-                    self.set_activity()?;
-                } else {
-                    info!("Track got unpaused, restarting activity");
-                    self.set_activity()?;
-                }
-            }
-            TrackUpdate::ImageUploaded(url) => {
-                info!("Done uploading the cover image");
-                self.track.art_url = url;
-                if !self.track.paused {
-                    self.set_activity()?;
-                }
-            }
-            TrackUpdate::None => {
-                info!("No more tracks are playing");
-                self.clear_activity()?;
+    if cli.players_list {
+        let players = PresenceService::players().await.unwrap_or_else(|e| {
+            error!("Failed to list the players: {e}");
+            exit(1);
+        });
+        if players.is_empty() {
+            println!("No players found");
+        }
+        for (player, track) in players {
+            match track {
+                Some(track) => println!(
+                    "{player}: {} {} by {}",
+                    track.status.as_str(),
+                    track.title,
+                    track.artist
+                ),
+                None => println!("{player}: no track"),
             }
         }
-        Ok(())
+        return;
     }
 
-    fn set_activity(&mut self) -> Result<(), Box<dyn Error>> {
-        let c = match self.client.as_mut() {
-            Some(c) => c,
-            None => {
-                let mut c = DiscordIpcClient::new(&self.app_id)?;
-                c.connect()?;
-                self.client = Some(c);
-                self.client.as_mut().unwrap()
+    let mut service = PresenceService::builder()
+        .matches(matches)
+        .build()
+        .await
+        .unwrap_or_else(|e| {
+            error!("{e}");
+            exit(1);
+        });
+
+    if cli.once {
+        match service.current_track().await {
+            Ok(Some(track)) => println!("{}", track.to_json()),
+            Ok(None) => {
+                info!("No track is playing");
+                exit(1);
+            }
+            Err(e) => {
+                error!("Failed to read the current track: {e}");
+                exit(1);
             }
-        };
-
-        let state_fmt = format!(
-            "by: {}{}",
-            self.track.artist,
-            (!self.track.album.is_empty())
-                .then(|| format!(", in: {}", self.track.album))
-                .unwrap_or_default()
-        );
-
-        let timestamps = Timestamps::new()
-            .start(self.track.start)
-            .end(self.track.start + self.track.length / 1000);
-
-        let fmt = format!("{} {}", self.track.title, self.track.artist);
-        let query = encode(&fmt);
-        let url = format!("https://yewtu.be/search?q={query}&type=video");
-
-        let mut buttons = vec![Button::new("Listen along", &url)];
-        if !self.hide_repository_button {
-            buttons.push(Button::new(
-                "View repository",
-                "https://github.com/faervan/music_presence",
-            ));
         }
-
-        let activity = Activity::new()
-            .state(&state_fmt)
-            .details(&self.track.title)
-            .assets(Assets::new().large_image(&self.track.art_url))
-            .activity_type(ActivityType::Listening)
-            .timestamps(timestamps)
-            .buttons(buttons);
-
-        c.set_activity(activity)?;
-
-        Ok(())
+        return;
     }
 
-    fn clear_activity(&mut self) -> Result<(), Box<dyn Error>> {
-        if let Some(c) = self.client.as_mut() {
-            c.clear_activity()?;
-            c.close()?;
-            self.client = None;
-        }
-
-        Ok(())
+    if let Err(e) = service.serve().await {
+        error!("{e}");
+        exit(1);
     }
 }
 
-#[derive(Clone, Debug)]
-enum TrackUpdate {
-    New(TrackInfo),
-    ImageUploaded(String),
-    /// No more tracks are playing
-    None,
-}
-
-mod size_parser {
-    use std::ffi::OsStr;
-
-    use clap::builder::TypedValueParser;
-
-    #[derive(Clone)]
-    pub struct SizeParser;
-
-    impl TypedValueParser for SizeParser {
-        type Value = (u32, u32);
-
-        fn parse_ref(
-            &self,
-            _cmd: &clap::Command,
-            _arg: Option<&clap::Arg>,
-            value: &OsStr,
-        ) -> Result<Self::Value, clap::Error> {
-            let value = value
-                .to_str()
-                .ok_or(clap::Error::new(clap::error::ErrorKind::InvalidUtf8))?;
-            if let Some((x, y)) = value.split_once('x') {
-                if let Ok(x) = x.parse() {
-                    if let Ok(y) = y.parse() {
-                        return Ok((x, y));
-                    }
-                }
-            }
-            Err(clap::Error::new(clap::error::ErrorKind::ValueValidation))
-        }
-    }
-}
-
-mod media_listener {
-    use std::{error::Error, process::Stdio};
-
-    use log::{error, info, warn};
-    use serde::Deserialize;
-    use tokio::{
-        io::{AsyncBufReadExt, BufReader},
-        process::Command,
-        sync::mpsc::UnboundedSender,
+/// Logs to stderr, and `--log-file` if given, exiting if that can't be opened
+fn init_logger(cli: &Cli) {
+    let mut logger = env_logger::builder();
+    let level = match cli.verbose {
+        _ if cli.quiet => Some(log::LevelFilter::Error),
+        0 => None,
+        1 => Some(log::LevelFilter::Info),
+        2 => Some(log::LevelFilter::Debug),
+        _ => Some(log::LevelFilter::Trace),
     };
-
-    use crate::{TrackUpdate, track_info::TrackInfo};
-
-    #[derive(Deserialize)]
-    #[allow(dead_code)]
-    struct ResponseBody {
-        status: String,
-        data: ResponseData,
+    if let Some(level) = level {
+        logger.filter_level(level);
     }
-
-    #[derive(Deserialize)]
-    struct ResponseData {
-        url: String,
+    if cli.log_format == LogFormat::Json {
+        logger.format(logging::format_json);
     }
-
-    pub async fn subscribe(
-        sender: UnboundedSender<TrackUpdate>,
-        player: String,
-        resize: Option<(u32, u32)>,
-    ) -> Result<(), Box<dyn Error>> {
-        let format = "'{ \
-           \"title\": \"{{title}}\", \
-           \"artist\": \"{{artist}}\", \
-           \"album\": \"{{album}}\", \
-           \"art_url\": \"{{mpris:artUrl}}\", \
-           \"length\": \"{{mpris:length}}\", \
-           \"status\": \"{{status}}\", \
-           \"player\": \"{{playerName}}\" \
-        }'";
-        let Ok(mut child) = Command::new("sh")
-            .arg("-c")
-            .arg(format!(
-                "playerctl \
-                    --follow metadata \
-                    --player {player} \
-                    --format {format}",
-            ))
-            .stdout(Stdio::piped())
-            .spawn()
-        else {
-            return Err("Failed to spawn playerctl. Are you sure it is installed?".into());
-        };
-
-        let stdout = child
-            .stdout
-            .take()
-            .ok_or("Child command has no handle to stdout")?;
-
-        let mut reader = BufReader::new(stdout).lines();
-        let mut last_track = String::new();
-
-        loop {
-            let Some(line) = reader.next_line().await.ok().flatten() else {
-                return Err("The playerctl child command reached EOF unexpectedly".into());
-            };
-            if let Ok(track) = serde_json::from_str::<TrackInfo>(&line) {
-                // If cover art is local, we need to upload first
-                if let Some(url) = track.art_is_local.then(|| track.art_url.clone()) {
-                    if url != last_track {
-                        last_track = url.clone();
-                        let sender = sender.clone();
-                        tokio::task::spawn(async move {
-                            if let Err(e) = upload_cover(sender, &url, resize).await {
-                                error!("Failed to upload image cover: {e:?}");
-                            }
-                        });
-                    }
-                }
-                sender.send(TrackUpdate::New(track))?;
-            } else if matches!(line.trim(), "") {
-                sender.send(TrackUpdate::None)?;
+    if let Some(path) = &cli.log_file {
+        match RotatingFile::open(path, cli.log_file_size, cli.log_file_count) {
+            Ok(file) => {
+                logger
+                    .target(env_logger::Target::Pipe(Box::new(Tee(io::stderr(), file))));
             }
-        }
-    }
-
-    async fn upload_cover(
-        sender: UnboundedSender<TrackUpdate>,
-        mut url: &str,
-        resize: Option<(u32, u32)>,
-    ) -> Result<(), Box<dyn Error>> {
-        if let Some(size) = resize {
-            let image = image::ImageReader::open(url)?.decode()?;
-            if size.0 > image.width() && size.1 > image.height() {
-                url = "/tmp/music_presence_tmp_cover.jpg";
-                image
-                    .resize_to_fill(size.0, size.1, image::imageops::FilterType::Triangle)
-                    .save(url)?;
-            }
-        }
-        let Ok(form) = reqwest::multipart::Form::new().file("file", url).await else {
-            if !std::fs::exists(url).is_ok_and(|b| b) {
-                warn!("File {url} does not exist or is a broken symlink.");
+            Err(e) => {
+                eprintln!("Failed to open log file {}: {e}", path.display());
+                exit(1);
             }
-            return Err("Failed to create reqwest::multipart::Form".into());
-        };
-        let response = reqwest::Client::new()
-            .post("https://tmpfiles.org/api/v1/upload")
-            .multipart(form)
-            .send()
-            .await?;
-        let img_url = response.json::<ResponseBody>().await?.data.url.replacen(
-            "https://tmpfiles.org/",
-            "https://tmpfiles.org/dl/",
-            1,
-        );
-        info!("got url: {img_url}");
-        sender.send(TrackUpdate::ImageUploaded(img_url))?;
-
-        Ok(())
-    }
-}
-
-mod track_info {
-    use std::time::{SystemTime, UNIX_EPOCH};
-
-    use serde::Deserialize;
-
-    #[derive(Debug, Default, Clone)]
-    pub(crate) struct TrackInfo {
-        pub title: String,
-        pub artist: String,
-        pub album: String,
-        pub art_url: String,
-        pub _player: String,
-        pub art_is_local: bool,
-        pub start: i64,
-        pub length: i64,
-        pub paused: bool,
-    }
-
-    impl<'de> Deserialize<'de> for TrackInfo {
-        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-        where
-            D: serde::Deserializer<'de>,
-        {
-            let map: serde_json::Map<String, serde_json::Value> =
-                Deserialize::deserialize(deserializer)?;
-
-            let mut art_url = map
-                .get("art_url")
-                .and_then(|v| v.as_str())
-                .unwrap_or_default()
-                .to_string();
-
-            let art_is_local = match art_url.strip_prefix("file://") {
-                Some(file) => {
-                    art_url = file.to_string();
-                    true
-                }
-                None => false,
-            };
-
-            Ok(TrackInfo {
-                title: map
-                    .get("title")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or_default()
-                    .to_string(),
-                artist: map
-                    .get("artist")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or_default()
-                    .to_string(),
-                album: map
-                    .get("album")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or_default()
-                    .to_string(),
-                _player: map
-                    .get("player")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or_default()
-                    .to_string(),
-                art_url,
-                art_is_local,
-                start: SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis() as i64,
-                length: map
-                    .get("length")
-                    .and_then(|v| v.as_str().and_then(|s| s.parse::<i64>().ok()))
-                    .unwrap_or_default(),
-                paused: map
-                    .get("status")
-                    .map(|v| !matches!(v.as_str(), Some("Playing")))
-                    .unwrap_or(true),
-            })
-        }
-    }
-
-    impl PartialEq for TrackInfo {
-        fn eq(&self, other: &Self) -> bool {
-            self.title == other.title
-                && self.artist == other.artist
-                && self.album == other.album
-                && self.art_is_local == other.art_is_local
-                && self.length == other.length
         }
     }
+    logger.init();
 }
@@ -0,0 +1,1073 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::{self, Cursor},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use image::{DynamicImage, ImageFormat, codecs::jpeg::JpegEncoder, imageops::FilterType};
+use log::{debug, error, info, warn};
+use reqwest::{StatusCode, header::CONTENT_TYPE, multipart::Part};
+use tempfile::NamedTempFile;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, BufReader},
+    process::Command,
+    sync::mpsc::UnboundedSender,
+    time::{Instant, sleep, sleep_until},
+};
+
+use crate::{
+    TrackUpdate,
+    art_fallback::CoverLookup,
+    backoff,
+    cover_cache::{self, CoverCache, RecentCovers},
+    cover_server::CoverServer,
+    image_host::ImageHost,
+    resize::Resize,
+    track_info::{self, ArtKind, TrackInfo},
+    truncate,
+};
+
+const RESTART_DELAY_MIN_MS: u64 = 1000;
+const RESTART_DELAY_MAX_MS: u64 = 60_000;
+
+/// Follows `players` with playerctl, restarting it up to `restart_max` times in a
+/// row if it exits
+pub async fn subscribe(
+    sender: UnboundedSender<TrackUpdate>,
+    players: Vec<String>,
+    clear_delay: Duration,
+    restart_max: usize,
+    uploader: Uploader,
+) -> Result<(), Box<dyn Error>> {
+    // Shared between restarts, so the same track isn't sent again
+    let mut tracker = Tracker::new(sender, players.clone(), uploader);
+    let mut restarts = 0;
+    loop {
+        if follow(&mut tracker, &players, clear_delay).await? {
+            restarts = 0;
+        }
+        if restarts >= restart_max {
+            return Err("The playerctl child command reached EOF unexpectedly".into());
+        }
+        let delay = backoff(RESTART_DELAY_MIN_MS, RESTART_DELAY_MAX_MS, restarts);
+        restarts += 1;
+        warn!(
+            "playerctl exited unexpectedly, restarting it in {}ms ({restarts}/{restart_max})",
+            delay.as_millis()
+        );
+        sleep(delay).await;
+    }
+}
+
+/// Runs playerctl until it exits, returns whether it reported any track
+async fn follow(
+    tracker: &mut Tracker,
+    players: &[String],
+    clear_delay: Duration,
+) -> Result<bool, Box<dyn Error>> {
+    let format = format();
+    let Ok(mut child) = Command::new("playerctl")
+        // Stop playerctl when re-subscribing after a config reload
+        .kill_on_drop(true)
+        .args(["--follow", "metadata", "--player", &players.join(",")])
+        .args(["--format", &format])
+        .stdout(Stdio::piped())
+        .spawn()
+    else {
+        return Err("Failed to spawn playerctl. Are you sure it is installed?".into());
+    };
+
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or("Child command has no handle to stdout")?;
+
+    let mut reader = BufReader::new(stdout).lines();
+    let mut received = false;
+    let mut record = String::new();
+    // When to clear the activity, unless a track arrives until then
+    let mut clear_at: Option<Instant> = None;
+
+    loop {
+        let line = tokio::select! {
+            line = reader.next_line() => line.ok().flatten(),
+            _ = sleep_until(clear_at.unwrap_or_else(Instant::now)),
+                if clear_at.is_some() =>
+            {
+                clear_at = None;
+                tracker.clear()?;
+                continue;
+            }
+        };
+        let Some(line) = line else {
+            return Ok(received);
+        };
+        match push_line(&mut record, &line) {
+            Line::Empty => {
+                clear_at.get_or_insert_with(|| Instant::now() + clear_delay);
+            }
+            Line::Incomplete => {}
+            Line::Record(fields) => {
+                if let Some(track) = TrackInfo::parse(&fields) {
+                    clear_at = None;
+                    received = true;
+                    tracker.update(track)?;
+                }
+            }
+        }
+    }
+}
+
+/// What a line of playerctl's output amounts to
+#[derive(Debug, PartialEq)]
+enum Line {
+    /// Nothing is playing
+    Empty,
+    /// Part of a record whose metadata contains newlines
+    Incomplete,
+    /// The fields of a complete record
+    Record(String),
+}
+
+/// Appends `line` to `record`, as metadata containing newlines spans multiple
+/// lines. Returns the fields and starts a new record once it is terminated.
+fn push_line(record: &mut String, line: &str) -> Line {
+    if !record.is_empty() {
+        record.push('\n');
+    }
+    record.push_str(line);
+    if record.trim().is_empty() {
+        record.clear();
+        return Line::Empty;
+    }
+    match record.strip_suffix(track_info::RECORD_SEPARATOR) {
+        Some(fields) => {
+            let fields = fields.to_string();
+            record.clear();
+            Line::Record(fields)
+        }
+        None => Line::Incomplete,
+    }
+}
+
+/// Reads one track per line from stdin, in the JSON format of
+/// [`TrackInfo::to_json`], until it is closed. An empty line or `null` clears
+/// the activity.
+pub async fn read_stdin(
+    sender: UnboundedSender<TrackUpdate>,
+    players: Vec<String>,
+    uploader: Uploader,
+) -> Result<(), Box<dyn Error>> {
+    info!("Reading tracks from stdin");
+    let reader = BufReader::new(tokio::io::stdin());
+    read_lines(reader, sender, players, uploader).await?;
+    info!("Reached the end of stdin");
+    Ok(())
+}
+
+/// Reads tracks from `reader` until its end, one JSON object per line, as with
+/// `--source stdin`. An empty line or `null` means that nothing is playing.
+pub async fn read_lines(
+    reader: impl AsyncBufRead + Unpin,
+    sender: UnboundedSender<TrackUpdate>,
+    players: Vec<String>,
+    uploader: Uploader,
+) -> Result<(), Box<dyn Error>> {
+    let mut tracker = Tracker::new(sender, players, uploader);
+    let mut lines = reader.lines();
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        if line.is_empty() || line == "null" {
+            tracker.clear()?;
+            continue;
+        }
+        match serde_json::from_str::<TrackInfo>(line) {
+            Ok(track) => tracker.update(track)?,
+            Err(e) => warn!("Ignoring invalid track: {e}"),
+        }
+    }
+    Ok(())
+}
+
+/// Reads the current track of the first available of `players` once, `None` if
+/// there is none
+pub async fn snapshot(players: &[String]) -> Result<Option<TrackInfo>, Box<dyn Error>> {
+    let Ok(output) = Command::new("playerctl")
+        .args(["--player", &players.join(","), "metadata"])
+        .args(["--format", &format()])
+        .output()
+        .await
+    else {
+        return Err("Failed to run playerctl. Are you sure it is installed?".into());
+    };
+    // playerctl fails if no player is running
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let record = String::from_utf8_lossy(&output.stdout);
+    Ok(record
+        .trim_end_matches('\n')
+        .strip_suffix(track_info::RECORD_SEPARATOR)
+        .and_then(TrackInfo::parse))
+}
+
+/// Lists the names of all players with their current track, if any
+pub async fn list() -> Result<Vec<(String, Option<TrackInfo>)>, Box<dyn Error>> {
+    let Ok(output) = Command::new("playerctl").arg("--list-all").output().await else {
+        return Err("Failed to run playerctl. Are you sure it is installed?".into());
+    };
+    // playerctl fails if no player is running
+    if !output.status.success() {
+        return Ok(vec![]);
+    }
+    let names = String::from_utf8_lossy(&output.stdout).into_owned();
+
+    let output = Command::new("playerctl")
+        .args(["--all-players", "metadata", "--format", &format()])
+        .output()
+        .await?;
+    let records = String::from_utf8_lossy(&output.stdout);
+    let mut tracks: HashMap<_, _> = records
+        .split(track_info::RECORD_SEPARATOR)
+        .filter_map(|record| TrackInfo::parse(record.trim_start_matches('\n')))
+        .map(|track| (track.player.clone(), track))
+        .collect();
+    Ok(names
+        .lines()
+        .map(|name| (name.to_string(), tracks.remove(name)))
+        .collect())
+}
+
+/// playerctl `--format` printing the [`track_info::FIELDS`]. Fields are separated
+/// by control characters instead of being embedded in JSON, so titles may contain
+/// quotes, backslashes or anything else
+fn format() -> String {
+    track_info::FIELDS
+        .iter()
+        .map(|field| format!("{{{{{field}}}}}"))
+        .collect::<Vec<_>>()
+        .join(&track_info::FIELD_SEPARATOR.to_string())
+        + &track_info::RECORD_SEPARATOR.to_string()
+}
+
+/// Decides which of the followed players is presented and uploads its covers
+pub struct Tracker {
+    sender: UnboundedSender<TrackUpdate>,
+    players: Vec<String>,
+    uploader: Uploader,
+    /// Track whose cover was uploaded last, so it isn't uploaded again whenever
+    /// the track is reported
+    last_track: Option<TrackInfo>,
+    /// Latest track of every player we received metadata from
+    tracks: HashMap<String, TrackInfo>,
+    /// The player whose track is currently presented
+    active: Option<String>,
+}
+
+impl Tracker {
+    pub fn new(
+        sender: UnboundedSender<TrackUpdate>,
+        players: Vec<String>,
+        uploader: Uploader,
+    ) -> Self {
+        Tracker {
+            sender,
+            players,
+            uploader,
+            last_track: None,
+            tracks: HashMap::new(),
+            active: None,
+        }
+    }
+
+    /// Handles new metadata of `track.player`
+    pub fn update(&mut self, track: TrackInfo) -> Result<(), Box<dyn Error>> {
+        let player = track.player.clone();
+        self.tracks.insert(player.clone(), track);
+
+        // Switch to the first playing player, stick to the current one otherwise
+        let playing = self.players.iter().find_map(|name| {
+            self.tracks
+                .values()
+                .find(|t| !t.is_paused() && is_instance_of(&t.player, name))
+        });
+        let new_active = match playing {
+            Some(t) => t.player.clone(),
+            None => self.active.clone().unwrap_or(player.clone()),
+        };
+        if new_active != player && self.active.as_ref() == Some(&new_active) {
+            return Ok(());
+        }
+        if self.active.as_ref() != Some(&new_active) {
+            info!("Switching to player {new_active}");
+        }
+        let mut track = self.tracks[&new_active].clone();
+        self.active = Some(new_active);
+        if self.uploader.static_image {
+            track.art_url.clear();
+            track.art_kind = ArtKind::Remote;
+            self.sender.send(TrackUpdate::New(track))?;
+            return Ok(());
+        }
+        if track.art_kind == ArtKind::Embedded && !self.uploader.embedded_art {
+            track.art_url.clear();
+            track.art_kind = ArtKind::Remote;
+        }
+        if self.uploader.rehost_untrusted {
+            track.check_art_host(&self.uploader.trusted_art_hosts);
+        } else if self.uploader.only_local
+            && track.art_kind == ArtKind::Remote
+            && track.art_url.starts_with("http://")
+        {
+            error!(
+                "Dropping cover {}, Discord only shows https urls and \
+                    --cover-only-local forbids uploading it",
+                track.art_url
+            );
+            track.art_url.clear();
+        }
+
+        // Found covers are sent right away, until then the track is shown without
+        if let Some(url) = self
+            .uploader
+            .art_fallback
+            .as_ref()
+            .filter(|_| track.art_url.is_empty())
+            .and_then(|lookup| lookup.cover(&track, &self.sender))
+        {
+            track.art_url = url;
+        }
+
+        // If cover art is local, we need to upload first
+        let uploaded = self
+            .last_track
+            .as_ref()
+            .is_some_and(|last| *last == track && last.art_url == track.art_url);
+        if track.art_kind.needs_upload() && !uploaded {
+            self.last_track = Some(track.clone());
+            let cover_of = track.clone();
+            let sender = self.sender.clone();
+            let uploader = self.uploader.clone();
+            tokio::task::spawn(async move {
+                if let Err(e) = uploader.upload(sender, &cover_of).await {
+                    #[cfg(feature = "metrics")]
+                    crate::metrics::UPLOAD_FAILURES.inc();
+                    error!("Failed to upload image cover: {e:?}");
+                }
+            });
+        }
+        self.sender.send(TrackUpdate::New(track))?;
+        Ok(())
+    }
+
+    /// Forgets the track of `player`, which went away
+    #[cfg(feature = "dbus")]
+    pub fn remove(&mut self, player: &str) -> Result<(), Box<dyn Error>> {
+        self.tracks.remove(player);
+        if self.active.as_deref() != Some(player) {
+            return Ok(());
+        }
+        self.active = None;
+        // Fall back to the first remaining player
+        let next = self.players.iter().find_map(|name| {
+            self.tracks
+                .values()
+                .find(|t| is_instance_of(&t.player, name))
+        });
+        match next {
+            Some(track) => self.update(track.clone()),
+            None => self.clear(),
+        }
+    }
+
+    /// Forgets all tracks
+    pub fn clear(&mut self) -> Result<(), Box<dyn Error>> {
+        self.tracks.clear();
+        self.active = None;
+        self.sender.send(TrackUpdate::None)?;
+        Ok(())
+    }
+}
+
+/// Whether playerctl can be found in `$PATH`
+#[cfg(feature = "dbus")]
+pub fn installed() -> bool {
+    std::env::var_os("PATH").is_some_and(|path| {
+        std::env::split_paths(&path).any(|dir| dir.join("playerctl").is_file())
+    })
+}
+
+/// playerctl's name matching every player
+pub const ANY_PLAYER: &str = "%any";
+
+/// Whether `player` is `name` or an instance of it, like `vlc.instance1234`.
+/// [`ANY_PLAYER`] matches everything but playerctld, which only mirrors the
+/// other players.
+pub fn is_instance_of(player: &str, name: &str) -> bool {
+    if name == ANY_PLAYER {
+        return player != "playerctld";
+    }
+    player
+        .strip_prefix(name)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+/// Creates an empty, uniquely named temporary file in `dir`, or the system's temp
+/// directory, with the extension of `format`, so the mime type is detected
+/// correctly when uploading it
+fn temp_file(format: ImageFormat, dir: Option<&Path>) -> io::Result<NamedTempFile> {
+    let suffix = format!(".{}", format.extensions_str()[0]);
+    let mut builder = tempfile::Builder::new();
+    builder.prefix("music_presence_cover").suffix(&suffix);
+    match dir {
+        Some(dir) => builder.tempfile_in(dir),
+        None => builder.tempfile(),
+    }
+}
+
+fn temp_path(file: &NamedTempFile) -> Result<&str, Box<dyn Error>> {
+    Ok(file
+        .path()
+        .to_str()
+        .ok_or("Temporary file path is not UTF-8")?)
+}
+
+/// Guesses the format of a cover from its data. Fails for AVIF, which the `image`
+/// crate only decodes with its `avif-native` feature and the system's dav1d
+/// library, and HEIC, which it doesn't support at all.
+fn cover_format(bytes: &[u8]) -> Result<Option<ImageFormat>, Box<dyn Error>> {
+    let brands = ftyp_brands(bytes);
+    let has = |names: &[&[u8; 4]]| brands.iter().any(|brand| names.contains(&brand));
+    if has(&[b"avif", b"avis"]) {
+        return Err(
+            "AVIF covers aren't supported, convert them to JPEG, PNG or WebP".into(),
+        );
+    }
+    // `mif1` and `msf1` are generic HEIF brands, which many AVIF files have as their
+    // major brand, so those are only HEIC if not compatible with AVIF
+    if has(&[
+        b"heic", b"heix", b"heim", b"heis", b"hevc", b"mif1", b"msf1",
+    ]) {
+        return Err(
+            "HEIC covers aren't supported, convert them to JPEG, PNG or WebP".into(),
+        );
+    }
+    Ok(image::guess_format(bytes).ok())
+}
+
+/// The major and compatible brands in the `ftyp` box that ISO BMFF files like AVIF
+/// and HEIC start with, none for other files
+fn ftyp_brands(bytes: &[u8]) -> Vec<[u8; 4]> {
+    if bytes.get(4..8) != Some(b"ftyp") {
+        return vec![];
+    }
+    let size = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    let ftyp = &bytes[..size.min(bytes.len())];
+    // The major brand is followed by its version, then the compatible brands
+    let major = ftyp.get(8..12).into_iter();
+    let compatible = ftyp.get(16..).unwrap_or_default().chunks_exact(4);
+    major
+        .chain(compatible)
+        .map(|brand| [brand[0], brand[1], brand[2], brand[3]])
+        .collect()
+}
+
+/// Decodes a cover in any format [`cover_format`] accepts
+fn decode_cover(bytes: &[u8]) -> Result<DynamicImage, Box<dyn Error>> {
+    let reader = match cover_format(bytes)? {
+        Some(format) => image::ImageReader::with_format(Cursor::new(bytes), format),
+        None => image::ImageReader::new(Cursor::new(bytes)),
+    };
+    Ok(reader.decode()?)
+}
+
+/// Re-encodes an image as JPEG with falling quality, halving its size whenever
+/// that isn't enough, until it takes at most `max` bytes
+fn shrink(bytes: &[u8], max: u64) -> Option<Vec<u8>> {
+    let mut image = DynamicImage::from(image::load_from_memory(bytes).ok()?.to_rgb8());
+    while image.width() >= 16 && image.height() >= 16 {
+        for quality in [85, 70, 55, 40] {
+            let mut shrunk = Vec::new();
+            let encoder = JpegEncoder::new_with_quality(&mut shrunk, quality);
+            image.write_with_encoder(encoder).ok()?;
+            if shrunk.len() as u64 <= max {
+                return Some(shrunk);
+            }
+        }
+        image = image.resize(image.width() / 2, image.height() / 2, FilterType::Triangle);
+    }
+    None
+}
+
+/// Checks that `url` serves an image, so Discord isn't handed a broken cover
+async fn verify_image(
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let response = client.head(url).send().await?.error_for_status()?;
+    let mime = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|mime| mime.to_str().ok())
+        .unwrap_or_default();
+    if !mime.is_empty() && !mime.starts_with("image/") {
+        return Err(format!("Uploaded cover {url} is no image but {mime}").into());
+    }
+    Ok(())
+}
+
+/// The mime type of a cover, empty if unknown, and its data
+type Picture = (String, Vec<u8>);
+
+/// Downloads a remote cover into its mime type and data
+async fn download(url: &str) -> Result<Picture, Box<dyn Error>> {
+    let response = reqwest::get(url).await?.error_for_status()?;
+    let mime = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|mime| mime.to_str().ok())
+        .and_then(|mime| mime.split(';').next())
+        .unwrap_or_default()
+        .to_string();
+    Ok((mime, response.bytes().await?.to_vec()))
+}
+
+/// Reads the front cover, or else the first picture, from the tags of the audio
+/// file at `path`, with its mime type if known
+#[cfg(feature = "embedded-art")]
+fn embedded_picture(path: &str) -> Result<Option<Picture>, Box<dyn Error>> {
+    use lofty::{file::TaggedFileExt, picture::PictureType};
+
+    let file = lofty::read_from_path(path)?;
+    let picture = file.tags().iter().find_map(|tag| {
+        tag.get_picture_type(PictureType::CoverFront)
+            .or_else(|| tag.pictures().first())
+    });
+    Ok(picture.map(|picture| {
+        let mime = picture.mime_type().map(|mime| mime.as_str().to_string());
+        (mime.unwrap_or_default(), picture.data().to_vec())
+    }))
+}
+
+/// Decodes a `data:[<mime type>][;base64],<data>` uri into its mime type and data
+fn decode_data_uri(uri: &str) -> Result<Picture, Box<dyn Error>> {
+    let (header, data) = uri
+        .strip_prefix("data:")
+        .and_then(|uri| uri.split_once(','))
+        .ok_or("Malformed data uri")?;
+    let (header, is_base64) = match header.strip_suffix(";base64") {
+        Some(header) => (header, true),
+        None => (header, false),
+    };
+    // Drop parameters like `;charset=utf-8`
+    let mime = header.split(';').next().unwrap_or_default().to_string();
+    let data = if is_base64 {
+        STANDARD.decode(data.trim())?
+    } else {
+        urlencoding::decode_binary(data.as_bytes()).into_owned()
+    };
+    Ok((mime, data))
+}
+
+/// Turns local covers into urls Discord can fetch
+#[derive(Clone)]
+pub struct Uploader {
+    pub resize: Option<Resize>,
+    /// How often to retry failed uploads
+    pub retries: usize,
+    /// Base and maximum delay between retries, in milliseconds
+    pub retry_ms: (u64, u64),
+    /// Failed uploads in a row after which uploading pauses for `pause`
+    pub failures_max: usize,
+    pub pause: Duration,
+    /// Shared between all uploads, so they know about each other's failures
+    pub breaker: Arc<Mutex<Breaker>>,
+    /// Larger covers are shrunk before uploading them
+    pub max_bytes: Option<u64>,
+    /// Check that uploaded covers can be fetched before showing them
+    pub verify: bool,
+    /// Looks up covers of tracks without one
+    pub art_fallback: Option<Arc<CoverLookup>>,
+    /// Hosts whose covers are shown without uploading them again
+    pub trusted_art_hosts: Vec<String>,
+    /// Upload covers of other hosts again, see `--rehost-untrusted-art`
+    pub rehost_untrusted: bool,
+    /// Never upload remote covers, see `--cover-only-local`
+    pub only_local: bool,
+    /// Where temporary cover files go, the system's temp directory if `None`
+    pub temp_dir: Option<PathBuf>,
+    /// Covers are replaced by `--static-large-image`, so none are uploaded
+    pub static_image: bool,
+    /// Read covers from the tags of local files, see `--extract-embedded-art`
+    pub embedded_art: bool,
+    pub cache: Arc<Mutex<CoverCache>>,
+    pub recent: Arc<Mutex<RecentCovers>>,
+    pub target: UploadTarget,
+    /// Only log which covers would be uploaded
+    pub dry_run: bool,
+}
+
+/// Counts failed uploads in a row, to stop trying while the image host is down
+#[derive(Default)]
+pub struct Breaker {
+    failures: usize,
+    /// Until when uploading is paused
+    paused_until: Option<Instant>,
+}
+
+impl Breaker {
+    fn is_paused(&self) -> bool {
+        self.paused_until
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    fn record(&mut self, success: bool, failures_max: usize, pause: Duration) {
+        if success {
+            if self.paused_until.take().is_some() {
+                info!("Uploading works again");
+            }
+            self.failures = 0;
+            return;
+        }
+        self.failures += 1;
+        if failures_max > 0 && self.failures >= failures_max {
+            warn!(
+                "{} uploads failed in a row, showing the default cover for {}s instead \
+                    of uploading",
+                self.failures,
+                pause.as_secs()
+            );
+            self.paused_until = Some(Instant::now() + pause);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum UploadTarget {
+    Host(Arc<dyn ImageHost>),
+    Local(CoverServer),
+}
+
+impl Uploader {
+    async fn upload(
+        &self,
+        sender: UnboundedSender<TrackUpdate>,
+        track: &TrackInfo,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut url = track.art_url.as_str();
+        let kind = track.art_kind;
+        let cover = |url: String| TrackUpdate::ImageUploaded {
+            track: track.clone(),
+            url,
+        };
+        // Show the default cover, or none, instead of waiting for one that never
+        // comes
+        if kind == ArtKind::Local && !std::fs::exists(url).is_ok_and(|b| b) {
+            warn!("File {url} does not exist or is a broken symlink.");
+            sender.send(cover(String::new()))?;
+            return Ok(());
+        }
+        if self.only_local && kind == ArtKind::Untrusted {
+            sender.send(cover(String::new()))?;
+            return Err(format!("Not uploading remote cover {url}").into());
+        }
+        if self.dry_run {
+            info!("Would upload cover {}", truncate(url, 100));
+            sender.send(cover(String::new()))?;
+            return Ok(());
+        }
+        // Unique per upload so concurrent uploads don't overwrite each other's
+        // files, which are deleted when dropped at the end of the upload
+        let data_file;
+        if kind != ArtKind::Local {
+            let (mime, bytes) = match kind {
+                ArtKind::Untrusted => download(url).await?,
+                #[cfg(feature = "embedded-art")]
+                ArtKind::Embedded => match embedded_picture(url)? {
+                    Some(picture) => picture,
+                    None => {
+                        debug!("{url} has no embedded cover");
+                        sender.send(cover(String::new()))?;
+                        return Ok(());
+                    }
+                },
+                _ => decode_data_uri(url)?,
+            };
+            let format = ImageFormat::from_mime_type(&mime)
+                .or_else(|| image::guess_format(&bytes).ok())
+                .ok_or(format!("Unsupported cover mime type `{mime}`"))?;
+            data_file = temp_file(format, self.temp_dir.as_deref())?;
+            std::fs::write(data_file.path(), bytes)?;
+            url = temp_path(&data_file)?;
+        }
+        // Covers shared by the tracks of an album, or of albums played in turns,
+        // are neither resized nor uploaded again
+        let Ok(source) = std::fs::read(url) else {
+            return Err(format!("Failed to read {url}").into());
+        };
+        let source_hash = cover_cache::hash(&source);
+        if let Some(img_url) = self.recent.lock().unwrap().get(&source_hash) {
+            debug!("Cover {url} was uploaded recently: {img_url}");
+            sender.send(cover(img_url))?;
+            return Ok(());
+        }
+        let input_format = cover_format(&source)
+            .map_err(|e| format!("Failed to decode cover {url}: {e}"))?;
+        debug!(
+            "Cover {url} is {}",
+            input_format.map_or("of unknown format", |format| format.extensions_str()[0])
+        );
+        // Discord can't show other formats, so those are converted even if the
+        // cover isn't resized
+        let displayable = matches!(
+            input_format,
+            Some(
+                ImageFormat::Jpeg
+                    | ImageFormat::Png
+                    | ImageFormat::WebP
+                    | ImageFormat::Gif
+            )
+        );
+        let resized_file;
+        if self.resize.is_some() || !displayable {
+            let mut image = decode_cover(&source)
+                .map_err(|e| format!("Failed to decode cover {url}: {e}"))?;
+            let mut changed = !displayable;
+            let mut format = ImageFormat::Jpeg;
+            if let Some(resize) = self.resize {
+                format = resize.format.into();
+                if let Some((width, height)) =
+                    resize.size.shrink(image.width(), image.height())
+                {
+                    image = image.resize(width, height, resize.filter.into());
+                    changed = true;
+                }
+            }
+            if changed {
+                resized_file = temp_file(format, self.temp_dir.as_deref())?;
+                url = temp_path(&resized_file)?;
+                // JPEG has no alpha channel
+                let image = match format {
+                    ImageFormat::Jpeg => image.to_rgb8().into(),
+                    _ => image,
+                };
+                image.save_with_format(url, format)?;
+            }
+        }
+        let Ok(mut bytes) = std::fs::read(url) else {
+            return Err(format!("Failed to read {url}").into());
+        };
+        let hash = cover_cache::hash(&bytes);
+
+        let image_host = match &self.target {
+            UploadTarget::Host(image_host) => image_host,
+            UploadTarget::Local(server) => {
+                let img_url = server.register(&hash, bytes);
+                info!("Serving cover at {img_url}");
+                self.recent
+                    .lock()
+                    .unwrap()
+                    .insert(source_hash, img_url.clone());
+                sender.send(cover(img_url))?;
+                return Ok(());
+            }
+        };
+        if let Some(img_url) = self.cache.lock().unwrap().get(&hash) {
+            info!("Cover was uploaded before: {img_url}");
+            sender.send(cover(img_url.to_string()))?;
+            return Ok(());
+        }
+
+        // Image hosts reject too large files
+        let shrunk_file;
+        if let Some(max) = self.max_bytes.filter(|max| bytes.len() as u64 > *max) {
+            let Some(shrunk) = shrink(&bytes, max) else {
+                warn!("Cover {url} can't be shrunk to {max} bytes, skipping it");
+                sender.send(cover(String::new()))?;
+                return Ok(());
+            };
+            info!(
+                "Shrunk cover from {} to {} bytes",
+                bytes.len(),
+                shrunk.len()
+            );
+            bytes = shrunk;
+            shrunk_file = temp_file(ImageFormat::Jpeg, self.temp_dir.as_deref())?;
+            std::fs::write(shrunk_file.path(), &bytes)?;
+            url = temp_path(&shrunk_file)?;
+        }
+
+        if self.breaker.lock().unwrap().is_paused() {
+            debug!("Not uploading {url}, uploading is paused after too many failures");
+            sender.send(cover(String::new()))?;
+            return Ok(());
+        }
+        let uploaded = self.upload_to(image_host, url, &sender, track).await;
+        self.breaker.lock().unwrap().record(
+            uploaded.is_ok(),
+            self.failures_max,
+            self.pause,
+        );
+        let img_url = uploaded?;
+        info!("got url: {img_url}");
+        #[cfg(feature = "metrics")]
+        crate::metrics::UPLOAD_BYTES.add(bytes.len() as u64);
+        let mut cache = self.cache.lock().unwrap();
+        cache.insert(hash, img_url.clone());
+        cache.save();
+        drop(cache);
+        self.recent
+            .lock()
+            .unwrap()
+            .insert(source_hash, img_url.clone());
+        sender.send(cover(img_url))?;
+
+        Ok(())
+    }
+
+    /// Uploads the file at `path` to `image_host`, retrying as configured, and
+    /// returns the url it can be fetched from
+    async fn upload_to(
+        &self,
+        image_host: &Arc<dyn ImageHost>,
+        path: &str,
+        sender: &UnboundedSender<TrackUpdate>,
+        track: &TrackInfo,
+    ) -> Result<String, Box<dyn Error>> {
+        let client = reqwest::Client::new();
+        let mut attempt = 0;
+        loop {
+            let Ok(file) = Part::file(path).await else {
+                return Err("Failed to create reqwest::multipart::Part".into());
+            };
+            #[cfg(feature = "metrics")]
+            crate::metrics::UPLOADS.inc();
+            let result: reqwest::Result<String> = async {
+                let response = image_host.request(&client, file).send().await?;
+                response.error_for_status()?.text().await
+            }
+            .await;
+            // Client errors like a too large cover won't go away by retrying
+            let retry = |e: &reqwest::Error| {
+                e.status().is_none_or(|status| {
+                    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+                })
+            };
+            let body = match result {
+                Ok(body) => body,
+                Err(e) if attempt < self.retries && retry(&e) => {
+                    let delay = backoff(self.retry_ms.0, self.retry_ms.1, attempt);
+                    warn!(
+                        "Failed to upload the cover, retrying in {}ms: {e}",
+                        delay.as_millis()
+                    );
+                    sleep(delay).await;
+                    attempt += 1;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let img_url = image_host.image_url(&body)?;
+            if !self.verify {
+                return Ok(img_url);
+            }
+            match verify_image(&client, &img_url).await {
+                Ok(()) => return Ok(img_url),
+                Err(e) if attempt < self.retries => {
+                    let delay = backoff(self.retry_ms.0, self.retry_ms.1, attempt);
+                    warn!(
+                        "Uploaded cover is broken, uploading it again in {}ms: {e}",
+                        delay.as_millis()
+                    );
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    // Show the default cover instead of a broken one
+                    sender.send(TrackUpdate::ImageUploaded {
+                        track: track.clone(),
+                        url: String::new(),
+                    })?;
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+    use tempfile::TempDir;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+    use crate::image_host::Host;
+
+    /// Uploads to tmpfiles.org, with its cover cache and temp files in `dir`
+    fn uploader(dir: &TempDir) -> Uploader {
+        let ttl = Duration::from_secs(3600);
+        Uploader {
+            resize: None,
+            retries: 0,
+            retry_ms: (0, 0),
+            failures_max: 0,
+            pause: Duration::ZERO,
+            breaker: Arc::default(),
+            max_bytes: None,
+            verify: false,
+            art_fallback: None,
+            trusted_art_hosts: Vec::new(),
+            rehost_untrusted: false,
+            only_local: false,
+            temp_dir: Some(dir.path().to_path_buf()),
+            static_image: false,
+            embedded_art: false,
+            cache: Arc::new(Mutex::new(CoverCache::load_from(
+                Some(dir.path().join("covers.json")),
+                16,
+                ttl,
+            ))),
+            recent: Arc::new(Mutex::new(RecentCovers::new(ttl))),
+            target: UploadTarget::Host(Host::Tmpfiles.uploader(None).into()),
+            dry_run: false,
+        }
+    }
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let image = DynamicImage::from(RgbImage::from_pixel(4, 3, Rgb([200, 40, 90])));
+        let mut bytes = Cursor::new(Vec::new());
+        image.write_to(&mut bytes, format).unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn webp_covers_are_decoded() {
+        let bytes = encode(ImageFormat::WebP);
+        assert_eq!(cover_format(&bytes).unwrap(), Some(ImageFormat::WebP));
+        let image = decode_cover(&bytes).unwrap();
+        assert_eq!((image.width(), image.height()), (4, 3));
+    }
+
+    #[test]
+    fn avif_covers_are_rejected() {
+        let bytes = encode(ImageFormat::Avif);
+        let error = decode_cover(&bytes).unwrap_err();
+        assert!(error.to_string().contains("AVIF"), "{error}");
+    }
+
+    #[test]
+    fn generic_heif_avif_covers_are_rejected_as_avif() {
+        // Major brand `mif1`, compatible with `avif`
+        let bytes = b"\0\0\0\x1cftypmif1\0\0\0\0mif1avifmiaf";
+        let error = decode_cover(bytes).unwrap_err();
+        assert!(error.to_string().contains("AVIF"), "{error}");
+    }
+
+    #[test]
+    fn heic_covers_are_rejected() {
+        // Just the `ftyp` box a HEIC file starts with
+        for bytes in [
+            b"\0\0\0\x18ftypheic\0\0\0\0mif1heic",
+            b"\0\0\0\x18ftypmif1\0\0\0\0mif1heic",
+        ] {
+            let error = decode_cover(bytes).unwrap_err();
+            assert!(error.to_string().contains("HEIC"), "{error}");
+        }
+    }
+
+    /// Uploads a PNG cover with `uploader` and returns the url it was replaced with
+    async fn upload_cover(uploader: Uploader, dir: &TempDir) -> String {
+        let path = dir.path().join("cover.png");
+        std::fs::write(&path, encode(ImageFormat::Png)).unwrap();
+        let track = TrackInfo {
+            art_url: path.to_str().unwrap().to_string(),
+            art_kind: ArtKind::Local,
+            ..Default::default()
+        };
+        let (sender, mut receiver) = unbounded_channel();
+        uploader.upload(sender, &track).await.unwrap();
+        match receiver.try_recv() {
+            Ok(TrackUpdate::ImageUploaded { url, .. }) => url,
+            update => panic!("unexpected update {update:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn dry_runs_show_no_cover() {
+        let dir = TempDir::new().unwrap();
+        let mut uploader = uploader(&dir);
+        uploader.dry_run = true;
+        assert_eq!(upload_cover(uploader, &dir).await, "");
+    }
+
+    #[tokio::test]
+    async fn covers_too_large_to_shrink_show_no_cover() {
+        let dir = TempDir::new().unwrap();
+        let mut uploader = uploader(&dir);
+        uploader.max_bytes = Some(1);
+        assert_eq!(upload_cover(uploader, &dir).await, "");
+    }
+
+    #[tokio::test]
+    async fn lines_are_tracked() {
+        let input = concat!(
+            r#"{"title": "Song", "artist": "Artist", "player": "kew"}"#,
+            "\n",
+            r#"{"title": "Song", "artist": "Artist", "player": "kew", "paused": true}"#,
+            "\n",
+            "not a track\n",
+            r#"{"title": "Song", "artist": "Artist", "player": "kew"}"#,
+            "\n",
+            "\n",
+        );
+        let (sender, mut receiver) = unbounded_channel();
+        let dir = TempDir::new().unwrap();
+        read_lines(
+            input.as_bytes(),
+            sender,
+            vec![ANY_PLAYER.into()],
+            uploader(&dir),
+        )
+        .await
+        .unwrap();
+
+        let updates: Vec<_> = std::iter::from_fn(|| receiver.try_recv().ok()).collect();
+        let [
+            TrackUpdate::New(first),
+            TrackUpdate::New(paused),
+            TrackUpdate::New(resumed),
+            TrackUpdate::None,
+        ] = &updates[..]
+        else {
+            panic!("unexpected updates {updates:?}");
+        };
+        assert!(!first.is_paused());
+        assert!(paused.is_paused());
+        assert!(!resumed.is_paused());
+        assert_eq!(resumed.title, "Song");
+        assert_eq!(resumed.artist, "Artist");
+    }
+
+    #[test]
+    fn records_span_lines() {
+        let mut record = String::new();
+        assert_eq!(push_line(&mut record, ""), Line::Empty);
+        assert_eq!(push_line(&mut record, "first\x1fline"), Line::Incomplete);
+        assert_eq!(push_line(&mut record, ""), Line::Incomplete);
+        assert_eq!(
+            push_line(&mut record, "last line\x1e"),
+            Line::Record("first\x1fline\n\nlast line".to_string())
+        );
+        assert!(record.is_empty());
+        assert_eq!(
+            push_line(&mut record, "next\x1e"),
+            Line::Record("next".to_string())
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    error::Error,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use axum::{Router, routing::get};
+use log::{error, info};
+use tokio::{net::TcpListener, time::Instant};
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+pub static TRACKS: Counter = Counter::new();
+pub static CLEARS: Counter = Counter::new();
+pub static UPLOADS: Counter = Counter::new();
+pub static UPLOAD_FAILURES: Counter = Counter::new();
+pub static UPLOAD_BYTES: Counter = Counter::new();
+pub static RECONNECTS: Counter = Counter::new();
+
+/// Name, help and value of every counter
+fn counters() -> [(&'static str, &'static str, &'static Counter); 6] {
+    [
+        ("tracks_total", "Tracks that started playing", &TRACKS),
+        (
+            "clears_total",
+            "Times no track was playing anymore",
+            &CLEARS,
+        ),
+        ("uploads_total", "Attempts to upload a cover", &UPLOADS),
+        (
+            "upload_failures_total",
+            "Covers that could not be uploaded or served",
+            &UPLOAD_FAILURES,
+        ),
+        (
+            "upload_bytes_total",
+            "Bytes of uploaded covers",
+            &UPLOAD_BYTES,
+        ),
+        (
+            "reconnects_total",
+            "Attempts to reconnect to Discord",
+            &RECONNECTS,
+        ),
+    ]
+}
+
+/// Serves the counters on `http://{addr}/metrics` in Prometheus' text format
+pub async fn serve(addr: SocketAddr) -> Result<(), Box<dyn Error>> {
+    let started = Instant::now();
+    let listener = TcpListener::bind(addr).await?;
+    let router =
+        Router::new().route("/metrics", get(move || async move { render(started) }));
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, router).await {
+            error!("Metrics server stopped: {e}");
+        }
+    });
+    info!("Serving metrics on http://{addr}/metrics");
+    Ok(())
+}
+
+fn render(started: Instant) -> String {
+    let mut text = format!(
+        "# HELP music_presence_uptime_seconds Seconds since music_presence started\n\
+        # TYPE music_presence_uptime_seconds gauge\n\
+        music_presence_uptime_seconds {}\n",
+        started.elapsed().as_secs()
+    );
+    for (name, help, counter) in counters() {
+        text.push_str(&format!(
+            "# HELP music_presence_{name} {help}\n\
+            # TYPE music_presence_{name} counter\n\
+            music_presence_{name} {}\n",
+            counter.0.load(Ordering::Relaxed)
+        ));
+    }
+    text
+}
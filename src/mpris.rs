@@ -0,0 +1,221 @@
+use std::{collections::HashMap, error::Error};
+
+use futures_util::{StreamExt, stream};
+use log::{debug, info};
+use tokio::sync::mpsc::UnboundedSender;
+use zbus::{
+    Connection, MatchRule, MessageStream,
+    fdo::{DBusProxy, PropertiesProxy},
+    message::Type,
+    names::{BusName, InterfaceName},
+    zvariant::{OwnedObjectPath, OwnedValue},
+};
+
+use crate::{
+    TrackUpdate,
+    media_listener::{Tracker, Uploader, is_instance_of},
+    track_info::{LoopStatus, PlaybackStatus, TrackInfo},
+};
+
+const BUS_NAME_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+/// Same as `media_listener::subscribe`, but talks to the players over D-Bus
+/// instead of going through playerctl
+pub async fn subscribe(
+    sender: UnboundedSender<TrackUpdate>,
+    players: Vec<String>,
+    uploader: Uploader,
+) -> Result<(), Box<dyn Error>> {
+    let connection = Connection::session().await?;
+    let dbus = DBusProxy::new(&connection).await?;
+
+    let properties_changed = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .path(OBJECT_PATH)?
+        .arg(0, PLAYER_INTERFACE)?
+        .build();
+    let seeked = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .interface(PLAYER_INTERFACE)?
+        .member("Seeked")?
+        .path(OBJECT_PATH)?
+        .build();
+    let mut changes = stream::select(
+        MessageStream::for_match_rule(properties_changed, &connection, None).await?,
+        MessageStream::for_match_rule(seeked, &connection, None).await?,
+    );
+    let mut owner_changes = dbus.receive_name_owner_changed().await?;
+
+    let follows = |name: &str| {
+        name.strip_prefix(BUS_NAME_PREFIX)
+            .filter(|player| players.iter().any(|name| is_instance_of(player, name)))
+            .map(String::from)
+    };
+    let mut tracker = Tracker::new(sender, players.clone(), uploader);
+    // Signals are sent from the unique name (like `:1.42`) of a player
+    let mut owners: HashMap<String, String> = HashMap::new();
+
+    for name in dbus.list_names().await? {
+        let Some(player) = follows(name.as_str()) else {
+            continue;
+        };
+        let owner = match dbus.get_name_owner(name.as_ref()).await {
+            Ok(owner) => owner,
+            // The player might have exited in the meantime
+            Err(e) => {
+                debug!("Failed to look up the owner of {name}: {e}");
+                continue;
+            }
+        };
+        owners.insert(owner.to_string(), player.clone());
+        match read_track(&connection, &player).await {
+            Ok(track) => tracker.update(track)?,
+            // The player might not have exported its interface yet
+            Err(e) => debug!("Failed to read metadata of {player}: {e}"),
+        }
+    }
+    info!("Listening to MPRIS players on D-Bus");
+
+    loop {
+        tokio::select! {
+            Some(message) = changes.next() => {
+                let message = message?;
+                let header = message.header();
+                let Some(player) = header.sender().and_then(|s| owners.get(s.as_str()))
+                else {
+                    continue;
+                };
+                match read_track(&connection, player).await {
+                    Ok(track) => tracker.update(track)?,
+                    // The player might have exited in the meantime
+                    Err(e) => debug!("Failed to read metadata of {player}: {e}"),
+                }
+            }
+            Some(signal) = owner_changes.next() => {
+                let args = signal.args()?;
+                let Some(player) = follows(args.name().as_str()) else {
+                    continue;
+                };
+                if let Some(old) = args.old_owner().as_ref() {
+                    owners.remove(old.as_str());
+                }
+                match args.new_owner().as_ref() {
+                    Some(new) => {
+                        owners.insert(new.to_string(), player.clone());
+                        match read_track(&connection, &player).await {
+                            Ok(track) => tracker.update(track)?,
+                            // The name is taken before the player interface is
+                            // exported, its first `PropertiesChanged` follows
+                            Err(e) => debug!("Failed to read metadata of {player}: {e}"),
+                        }
+                    }
+                    None => tracker.remove(&player)?,
+                }
+            }
+            else => return Err("The D-Bus connection was closed".into()),
+        }
+    }
+}
+
+/// Same as `media_listener::snapshot`, for systems without playerctl
+pub async fn snapshot(players: &[String]) -> Result<Option<TrackInfo>, Box<dyn Error>> {
+    let connection = Connection::session().await?;
+    let names = DBusProxy::new(&connection).await?.list_names().await?;
+    for name in players {
+        let player = names.iter().find_map(|bus_name| {
+            bus_name
+                .as_str()
+                .strip_prefix(BUS_NAME_PREFIX)
+                .filter(|player| is_instance_of(player, name))
+        });
+        if let Some(player) = player {
+            return read_track(&connection, player).await.map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// Reads the current metadata and playback status of `player`
+async fn read_track(
+    connection: &Connection,
+    player: &str,
+) -> Result<TrackInfo, Box<dyn Error>> {
+    let properties = PropertiesProxy::builder(connection)
+        .destination(BusName::try_from(format!("{BUS_NAME_PREFIX}{player}"))?)?
+        .path(OBJECT_PATH)?
+        .build()
+        .await?
+        .get_all(InterfaceName::from_static_str_unchecked(PLAYER_INTERFACE))
+        .await?;
+    let metadata: HashMap<String, OwnedValue> = properties
+        .get("Metadata")
+        .and_then(|value| value.try_clone().ok()?.try_into().ok())
+        .unwrap_or_default();
+
+    let string = |map: &HashMap<String, OwnedValue>, key: &str| {
+        map.get(key)
+            .and_then(|value| String::try_from(value.try_clone().ok()?).ok())
+            .unwrap_or_default()
+    };
+    // Some players send a single string instead of a list
+    let artists = metadata
+        .get("xesam:artist")
+        .and_then(|value| {
+            let value = value.try_clone().ok()?;
+            Vec::<String>::try_from(value.try_clone().ok()?)
+                .ok()
+                .or_else(|| String::try_from(value).ok().map(|artist| vec![artist]))
+        })
+        .unwrap_or_default();
+    // `x` per spec, but some players use `t`
+    let integer = |value: Option<&OwnedValue>| {
+        value
+            .and_then(|value| {
+                i64::try_from(value).ok().or_else(|| {
+                    u64::try_from(value)
+                        .ok()
+                        .and_then(|v| i64::try_from(v).ok())
+                })
+            })
+            .unwrap_or_default()
+    };
+    // An object path per spec, but some players send a plain string
+    let track_id = metadata
+        .get("mpris:trackid")
+        .and_then(|value| OwnedObjectPath::try_from(value.try_clone().ok()?).ok())
+        .map(|path| path.to_string())
+        .unwrap_or_else(|| string(&metadata, "mpris:trackid"));
+
+    // An integer per spec, but some players send a string like `3/12`
+    let (track_number, track_total) = match integer(metadata.get("xesam:trackNumber")) {
+        0 => TrackInfo::parse_track_number(&string(&metadata, "xesam:trackNumber")),
+        number => (u16::try_from(number).unwrap_or_default(), 0),
+    };
+
+    let mut track = TrackInfo {
+        track_id: TrackInfo::valid_track_id(track_id),
+        track_number,
+        track_total,
+        length: integer(metadata.get("mpris:length")),
+        position: integer(properties.get("Position")),
+        status: PlaybackStatus::parse(&string(&properties, "PlaybackStatus")),
+        loop_status: LoopStatus::parse(&string(&properties, "LoopStatus")),
+        shuffle: properties
+            .get("Shuffle")
+            .and_then(|value| bool::try_from(value).ok())
+            .unwrap_or_default(),
+        ..TrackInfo::new(
+            &string(&metadata, "xesam:title"),
+            artists,
+            &string(&metadata, "xesam:album"),
+            &string(&metadata, "mpris:artUrl"),
+            player,
+        )
+    };
+    track.use_embedded_art(&string(&metadata, "xesam:url"));
+    Ok(track)
+}
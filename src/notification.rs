@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+
+use log::warn;
+use tokio::sync::mpsc::UnboundedReceiver;
+use zbus::{Connection, zvariant::Value};
+
+use crate::{
+    TrackUpdate,
+    track_info::{ArtKind, TrackInfo},
+};
+
+/// Shows a desktop notification for every new track received on `updates`
+pub async fn run(mut updates: UnboundedReceiver<TrackUpdate>) {
+    let connection = match Connection::session().await {
+        Ok(connection) => connection,
+        Err(e) => {
+            warn!("Failed to connect to the session bus, not notifying: {e}");
+            return;
+        }
+    };
+    let mut last: Option<TrackInfo> = None;
+    // Lets every notification replace the previous one
+    let mut id = 0;
+
+    while let Some(update) = updates.recv().await {
+        let TrackUpdate::New(track) = update else {
+            continue;
+        };
+        // Pausing and resuming is not worth a notification
+        if last.as_ref() == Some(&track) {
+            continue;
+        }
+        match notify(&connection, id, &track).await {
+            Ok(new_id) => id = new_id,
+            Err(e) => warn!("Failed to send notification: {e}"),
+        }
+        last = Some(track);
+    }
+}
+
+/// See https://specifications.freedesktop.org/notification-spec/latest/protocol.html
+async fn notify(
+    connection: &Connection,
+    id: u32,
+    track: &TrackInfo,
+) -> zbus::Result<u32> {
+    let icon = match track.art_kind {
+        ArtKind::Local => track.art_url.as_str(),
+        _ => "",
+    };
+    let body = match track.artist.is_empty() {
+        true => String::new(),
+        false => format!("by {}", track.artist),
+    };
+    let reply = connection
+        .call_method(
+            Some("org.freedesktop.Notifications"),
+            "/org/freedesktop/Notifications",
+            Some("org.freedesktop.Notifications"),
+            "Notify",
+            &(
+                "music_presence",
+                id,
+                icon,
+                track.title.as_str(),
+                body,
+                Vec::<&str>::new(),
+                HashMap::<&str, Value>::new(),
+                -1,
+            ),
+        )
+        .await?;
+    reply.body().deserialize()
+}
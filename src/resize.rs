@@ -0,0 +1,53 @@
+use clap::ValueEnum;
+use image::{ImageFormat, imageops::FilterType};
+use serde::{Deserialize, Serialize};
+
+use crate::size_parser::Size;
+
+/// How local covers are resized before uploading
+#[derive(Clone, Copy, PartialEq)]
+pub struct Resize {
+    pub size: Size,
+    pub filter: ResizeFilter,
+    pub format: CoverFormat,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl From<ResizeFilter> for FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CoverFormat {
+    Jpg,
+    Png,
+    Webp,
+}
+
+impl From<CoverFormat> for ImageFormat {
+    fn from(format: CoverFormat) -> Self {
+        match format {
+            CoverFormat::Jpg => ImageFormat::Jpeg,
+            CoverFormat::Png => ImageFormat::Png,
+            CoverFormat::Webp => ImageFormat::WebP,
+        }
+    }
+}
@@ -0,0 +1,439 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fs,
+    io::ErrorKind,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, info, warn};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc::UnboundedReceiver, time::sleep_until};
+
+use crate::{TrackUpdate, track_info::TrackInfo};
+
+/// Maximum number of scrobbles kept while the service is unreachable
+const QUEUE_SIZE: usize = 1000;
+/// How long to wait before submitting queued scrobbles again after a failure
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// A track that was listened to long enough to be submitted
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Scrobble {
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub player: String,
+    /// When the track started playing, in seconds since the epoch
+    pub timestamp: u64,
+    /// Length of the track in seconds, 0 if unknown
+    pub duration: u64,
+}
+
+/// Why a service didn't take a scrobble or now playing update
+#[derive(Debug, thiserror::Error)]
+pub enum ScrobbleError {
+    /// The service couldn't be reached or failed on its side
+    #[error("{0}")]
+    Unavailable(Box<dyn Error + Send + Sync>),
+    /// The service refused the request itself, e.g. because of missing metadata
+    #[error("rejected: {0}")]
+    Rejected(Box<dyn Error + Send + Sync>),
+}
+
+impl ScrobbleError {
+    /// Whether submitting again might succeed. Rejected scrobbles never will, so
+    /// they aren't kept in the queue.
+    fn is_retryable(&self) -> bool {
+        match self {
+            ScrobbleError::Unavailable(_) => true,
+            ScrobbleError::Rejected(_) => false,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ScrobbleError {
+    /// Client errors, except for rate limiting, are rejections
+    fn from(e: reqwest::Error) -> Self {
+        match e.status() {
+            Some(status)
+                if status.is_client_error()
+                    && status != StatusCode::TOO_MANY_REQUESTS =>
+            {
+                ScrobbleError::Rejected(e.into())
+            }
+            _ => ScrobbleError::Unavailable(e.into()),
+        }
+    }
+}
+
+/// A service listens get submitted to, like Last.fm or ListenBrainz
+pub trait ScrobbleService {
+    const NAME: &str;
+    /// Used in file names, like the one of the [`Queue`]
+    const ID: &str;
+
+    /// Called once before anything is submitted, e.g. to authenticate
+    async fn init(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
+    async fn now_playing(&self, track: &TrackInfo) -> Result<(), ScrobbleError>;
+
+    async fn scrobble(&self, scrobble: &Scrobble) -> Result<(), ScrobbleError>;
+}
+
+/// Scrobbles that couldn't be submitted yet, oldest first. They are persisted to
+/// `$XDG_CACHE_HOME/music_presence/<service>_queue.json` so they survive restarts.
+struct Queue {
+    scrobbles: VecDeque<Scrobble>,
+    path: Option<PathBuf>,
+}
+
+impl Queue {
+    fn load(id: &str) -> Self {
+        let path = dirs::cache_dir()
+            .map(|dir| dir.join("music_presence").join(format!("{id}_queue.json")));
+        let scrobbles = path
+            .as_ref()
+            .and_then(|path| match fs::read_to_string(path) {
+                Ok(content) => serde_json::from_str(&content)
+                    .inspect_err(|e| {
+                        warn!("Ignoring corrupt scrobble queue {}: {e}", path.display())
+                    })
+                    .ok(),
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => {
+                    warn!("Failed to read scrobble queue {}: {e}", path.display());
+                    None
+                }
+            })
+            .unwrap_or_default();
+        let mut queue = Queue { scrobbles, path };
+        queue.truncate();
+        queue
+    }
+
+    /// Writes the queue to disk, removing the file once it is empty
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let result = if self.scrobbles.is_empty() {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e.to_string()),
+                _ => Ok(()),
+            }
+        } else {
+            serde_json::to_string(&self.scrobbles)
+                .map_err(|e| e.to_string())
+                .and_then(|json| {
+                    if let Some(dir) = path.parent() {
+                        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+                    }
+                    fs::write(path, json).map_err(|e| e.to_string())
+                })
+        };
+        if let Err(e) = result {
+            warn!("Failed to save scrobble queue to {}: {e}", path.display());
+        }
+    }
+
+    fn push(&mut self, scrobble: Scrobble) {
+        self.scrobbles.push_back(scrobble);
+        self.truncate();
+        self.save();
+    }
+
+    /// Drops the oldest scrobbles if there are more than [`QUEUE_SIZE`]
+    fn truncate(&mut self) {
+        let excess = self.scrobbles.len().saturating_sub(QUEUE_SIZE);
+        if excess > 0 {
+            warn!("Scrobble queue is full, dropping the {excess} oldest scrobbles");
+            self.scrobbles.drain(..excess);
+        }
+    }
+}
+
+/// The track that is currently played
+struct Listen {
+    track: TrackInfo,
+    timestamp: u64,
+    /// Play time before the last pause
+    played: Duration,
+    /// When the track was last started or unpaused, `None` while paused
+    resumed: Option<Instant>,
+}
+
+impl Listen {
+    fn new(track: TrackInfo) -> Self {
+        Listen {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            played: Duration::ZERO,
+            resumed: (!track.is_paused()).then(Instant::now),
+            track,
+        }
+    }
+
+    /// Returns `true` if the track got unpaused
+    fn set_paused(&mut self, paused: bool) -> bool {
+        match (paused, self.resumed) {
+            (true, Some(resumed)) => {
+                self.played += resumed.elapsed();
+                self.resumed = None;
+                false
+            }
+            (false, None) => {
+                self.resumed = Some(Instant::now());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the track was played for half its length or 4 minutes, whichever
+    /// comes first. Tracks shorter than 30 seconds never count.
+    fn counts(&self) -> bool {
+        let length = Duration::from_micros(self.track.length.max(0) as u64);
+        let played = self.played + self.resumed.map(|r| r.elapsed()).unwrap_or_default();
+        let threshold = if length.is_zero() {
+            Duration::from_secs(4 * 60)
+        } else {
+            (length / 2).min(Duration::from_secs(4 * 60))
+        };
+        (length.is_zero() || length > Duration::from_secs(30)) && played >= threshold
+    }
+
+    /// Whether the track has an artist and a title, which the services require
+    fn is_complete(&self) -> bool {
+        let complete =
+            !self.track.artist.trim().is_empty() && !self.track.title.trim().is_empty();
+        if !complete {
+            info!(
+                "Not scrobbling {:?} by {:?}, it lacks an artist or title",
+                self.track.title, self.track.artist
+            );
+        }
+        complete
+    }
+
+    fn into_scrobble(self) -> Scrobble {
+        Scrobble {
+            duration: self.track.length.max(0) as u64 / 1_000_000,
+            title: self.track.title,
+            artist: self.track.artist,
+            album: self.track.album,
+            player: self.track.player,
+            timestamp: self.timestamp,
+        }
+    }
+}
+
+/// Submits the tracks from `updates` to `service` until the sender is dropped
+pub async fn run<S: ScrobbleService>(
+    mut service: S,
+    mut updates: UnboundedReceiver<TrackUpdate>,
+) {
+    if let Err(e) = service.init().await {
+        error!("Failed to set up {}, not scrobbling: {e}", S::NAME);
+        return;
+    }
+
+    let mut current: Option<Listen> = None;
+    let mut queue = Queue::load(S::ID);
+    if !queue.scrobbles.is_empty() {
+        info!(
+            "Submitting {} scrobbles queued for {}",
+            queue.scrobbles.len(),
+            S::NAME
+        );
+    }
+    // When to submit the queued scrobbles again, `None` if there are none
+    let mut retry_at = Some(Instant::now()).filter(|_| !queue.scrobbles.is_empty());
+
+    loop {
+        let update = tokio::select! {
+            update = updates.recv() => match update {
+                Some(update) => update,
+                None => break,
+            },
+            _ = sleep_until(retry_at.unwrap_or_else(Instant::now).into()), if retry_at.is_some() => {
+                retry_at = submit(&service, &mut queue).await;
+                continue;
+            }
+        };
+        let finished = match update {
+            TrackUpdate::New(track) => match current.as_mut() {
+                Some(listen) if listen.track == track => {
+                    if listen.set_paused(track.is_paused())
+                        && now_playing(&service, &track).await
+                        && retry_at.is_some()
+                    {
+                        // The service is reachable again
+                        retry_at = submit(&service, &mut queue).await;
+                    }
+                    None
+                }
+                _ => {
+                    if !track.is_paused()
+                        && now_playing(&service, &track).await
+                        && retry_at.is_some()
+                    {
+                        retry_at = submit(&service, &mut queue).await;
+                    }
+                    current.replace(Listen::new(track))
+                }
+            },
+            TrackUpdate::None => current.take(),
+            TrackUpdate::ImageUploaded { .. } => None,
+        };
+
+        if let Some(listen) = finished.filter(Listen::counts).filter(Listen::is_complete)
+        {
+            queue.push(listen.into_scrobble());
+            retry_at = submit(&service, &mut queue).await;
+        }
+    }
+
+    // Shutting down, don't lose the current track if it already counts
+    if let Some(listen) = current.filter(Listen::counts).filter(Listen::is_complete) {
+        queue.push(listen.into_scrobble());
+    }
+    if !queue.scrobbles.is_empty() {
+        submit(&service, &mut queue).await;
+    }
+}
+
+/// Returns `false` if the service couldn't be reached
+async fn now_playing<S: ScrobbleService>(service: &S, track: &TrackInfo) -> bool {
+    match service.now_playing(track).await {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Failed to update now playing on {}: {e}", S::NAME);
+            !e.is_retryable()
+        }
+    }
+}
+
+/// Submits the queued scrobbles with their original timestamps, keeping them if
+/// the service is unreachable and dropping those it rejects. Returns when to try
+/// again if some are left.
+async fn submit<S: ScrobbleService>(service: &S, queue: &mut Queue) -> Option<Instant> {
+    let mut retry_at = None;
+    while let Some(scrobble) = queue.scrobbles.front() {
+        match service.scrobble(scrobble).await {
+            Ok(()) => info!(
+                "Scrobbled {} by {} to {}",
+                scrobble.title,
+                scrobble.artist,
+                S::NAME
+            ),
+            Err(e) if e.is_retryable() => {
+                warn!(
+                    "Failed to scrobble to {}, {} scrobbles are queued: {e}",
+                    S::NAME,
+                    queue.scrobbles.len()
+                );
+                retry_at = Some(Instant::now() + RETRY_INTERVAL);
+                break;
+            }
+            Err(e) => error!(
+                "Dropping the scrobble of {} by {}, {} {e}",
+                scrobble.title,
+                scrobble.artist,
+                S::NAME
+            ),
+        }
+        queue.scrobbles.pop_front();
+    }
+    queue.save();
+    retry_at
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Answers the scrobbles with `results` in order, then accepts all
+    struct Fake {
+        results: Mutex<VecDeque<Result<(), ScrobbleError>>>,
+    }
+
+    impl ScrobbleService for Fake {
+        const NAME: &str = "Fake";
+        const ID: &str = "fake";
+
+        async fn now_playing(&self, _track: &TrackInfo) -> Result<(), ScrobbleError> {
+            Ok(())
+        }
+
+        async fn scrobble(&self, _scrobble: &Scrobble) -> Result<(), ScrobbleError> {
+            self.results.lock().unwrap().pop_front().unwrap_or(Ok(()))
+        }
+    }
+
+    fn queue(titles: &[&str]) -> Queue {
+        let scrobbles = titles
+            .iter()
+            .map(|title| Scrobble {
+                title: title.to_string(),
+                artist: "Artist".to_string(),
+                album: String::new(),
+                player: "player".to_string(),
+                timestamp: 0,
+                duration: 0,
+            })
+            .collect();
+        Queue {
+            scrobbles,
+            path: None,
+        }
+    }
+
+    fn titles(queue: &Queue) -> Vec<&str> {
+        queue.scrobbles.iter().map(|s| s.title.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn rejected_scrobbles_are_dropped() {
+        let service = Fake {
+            results: Mutex::new(VecDeque::from([
+                Ok(()),
+                Err(ScrobbleError::Rejected("invalid".into())),
+            ])),
+        };
+        let mut queue = queue(&["a", "b", "c"]);
+        assert!(submit(&service, &mut queue).await.is_none());
+        assert!(queue.scrobbles.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unavailable_service_keeps_the_queue() {
+        let service = Fake {
+            results: Mutex::new(VecDeque::from([
+                Ok(()),
+                Err(ScrobbleError::Unavailable("offline".into())),
+            ])),
+        };
+        let mut queue = queue(&["a", "b", "c"]);
+        assert!(submit(&service, &mut queue).await.is_some());
+        assert_eq!(titles(&queue), ["b", "c"]);
+    }
+
+    #[test]
+    fn tracks_without_artist_or_title_are_incomplete() {
+        let listen = |title: &str, artist: &str| {
+            Listen::new(TrackInfo::new(title, vec![artist.into()], "", "", "player"))
+        };
+        assert!(listen("Song", "Artist").is_complete());
+        assert!(!listen("Song", "").is_complete());
+        assert!(!listen(" ", "Artist").is_complete());
+    }
+}
@@ -0,0 +1,437 @@
+use std::{
+    error::Error,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{debug, error, info, warn};
+#[cfg(feature = "systemd")]
+use sd_notify::NotifyState;
+use tokio::{
+    signal::unix::{Signal, SignalKind, signal},
+    sync::mpsc,
+    time::{Instant, sleep_until, timeout_at},
+};
+
+#[cfg(feature = "metrics")]
+use crate::metrics;
+#[cfg(feature = "notify")]
+use crate::notification;
+#[cfg(feature = "systemd")]
+use crate::systemd;
+use crate::{
+    CONFIG_DEBOUNCE, PresenceService, RECONNECT_DELAY_MAX, RECONNECT_DELAY_MIN,
+    SHUTDOWN_TIMEOUT, TrackInfo, TrackUpdate, config, control::ControlSocket,
+    cover_cache::RecentCovers, hook, lastfm::Lastfm, listenbrainz::ListenBrainz,
+    scrobbler, spawn_listener,
+};
+
+impl PresenceService {
+    /// Follows the configured players, or stdin, and shows their tracks until
+    /// interrupted. Unlike [`run`](Self::run), this also serves the control socket
+    /// and metrics, scrobbles, holds back tracks for `--min-track-secs` and
+    /// reloads the config file. Fails if any of that can't be set up.
+    pub async fn serve(self) -> Result<(), Box<dyn Error>> {
+        let PresenceService {
+            app: mut args,
+            mut uploader,
+            matches,
+        } = self;
+        let (sx, mut rx) = mpsc::unbounded_channel();
+        let mut listener = spawn_listener(sx.clone(), &args, uploader.clone());
+
+        #[cfg(feature = "metrics")]
+        if let Some(addr) = args.metrics_addr {
+            metrics::serve(addr)
+                .await
+                .map_err(|e| format!("Failed to serve metrics on {addr}: {e}"))?;
+        }
+
+        let (control_sx, mut control_rx) = mpsc::unbounded_channel();
+        // Removes the socket again when dropped at the end of `serve`
+        let _control_socket = args
+            .control_socket
+            .as_deref()
+            .map(|path| {
+                ControlSocket::bind(path, control_sx).map_err(|e| {
+                    format!("Failed to create control socket {}: {e}", path.display())
+                })
+            })
+            .transpose()?;
+
+        let mut hangup = handle_signal(SignalKind::hangup(), "SIGHUP")?;
+        let (config_sx, mut config_rx) = mpsc::unbounded_channel();
+        // Stops watching when dropped at the end of `serve`. Options that don't come
+        // from the command line have no config file to reload.
+        let _config_watcher = match matches {
+            Some(_) => config::watch(args.config.as_deref(), config_sx).unwrap_or_else(|e| {
+                warn!("Failed to watch the config file, reload it with SIGHUP instead: {e}");
+                None
+            }),
+            None => None,
+        };
+        let mut interrupt = handle_signal(SignalKind::interrupt(), "SIGINT")?;
+        let mut terminate = handle_signal(SignalKind::terminate(), "SIGTERM")?;
+
+        // Every scrobbler (and the notifier and the --on-change hook) gets a copy of all
+        // updates
+        let mut scrobblers = vec![];
+        let mut scrobbler_tasks = vec![];
+        if let (Some(key), Some(secret)) = (&args.lastfm_key, &args.lastfm_secret) {
+            let (sx, rx) = mpsc::unbounded_channel();
+            scrobbler_tasks.push(tokio::spawn(scrobbler::run(
+                Lastfm::new(key.clone(), secret.clone()),
+                rx,
+            )));
+            scrobblers.push(sx);
+        }
+        if let Some(token) = &args.listenbrainz_token {
+            let (sx, rx) = mpsc::unbounded_channel();
+            scrobbler_tasks.push(tokio::spawn(scrobbler::run(
+                ListenBrainz::new(token.clone()),
+                rx,
+            )));
+            scrobblers.push(sx);
+        }
+        #[cfg(feature = "notify")]
+        if args.notify {
+            let (sx, rx) = mpsc::unbounded_channel();
+            tokio::spawn(notification::run(rx));
+            scrobblers.push(sx);
+        }
+        if let Some(command) = &args.on_change {
+            let (sx, rx) = mpsc::unbounded_channel();
+            tokio::spawn(hook::run(command.clone(), rx));
+            scrobblers.push(sx);
+        }
+
+        // When to try reconnecting to Discord, if it went away while a track is shown
+        let mut reconnect_at: Option<Instant> = None;
+        let mut reconnect_delay = RECONNECT_DELAY_MIN;
+        // Whether we ever got a connection, `--connect-timeout` only applies before that
+        let mut connected = false;
+        let started = Instant::now();
+        let mut position_poll = args.position_poll();
+        let mut hold = Hold::default();
+        // When to reload the config file, after SIGHUP or once it stopped changing
+        let mut reload_at: Option<Instant> = None;
+        // Whether systemd was told that we are ready, which happens once the first
+        // update is handled
+        #[cfg(feature = "systemd")]
+        let mut ready = false;
+
+        loop {
+            // Whether `update` is a held back track, which was already prepared
+            let mut released = false;
+            let mut update = tokio::select! {
+                update = rx.recv() => match update {
+                    Some(update) => update,
+                    None => {
+                        warn!("Stopped receiving tracks, exiting");
+                        break;
+                    }
+                },
+                _ = &mut listener => {
+                    warn!("Stopped receiving tracks, exiting");
+                    break;
+                }
+                _ = interrupt.recv() => {
+                    info!("Received SIGINT, exiting");
+                    break;
+                }
+                _ = terminate.recv() => {
+                    info!("Received SIGTERM, exiting");
+                    break;
+                }
+                Some(request) = control_rx.recv() => {
+                    let reply = args.control(request.command).await;
+                    let _ = request.reply.send(reply);
+                    if args.disconnected() {
+                        reconnect_at.get_or_insert_with(|| Instant::now() + reconnect_delay);
+                    }
+                    continue;
+                }
+                _ = hangup.recv() => {
+                    reload_at = matches.is_some().then(Instant::now);
+                    continue;
+                }
+                Some(()) = config_rx.recv() => {
+                    reload_at = Some(Instant::now() + CONFIG_DEBOUNCE);
+                    continue;
+                }
+                _ = sleep_until(reload_at.unwrap_or_else(Instant::now)), if reload_at.is_some() => {
+                    reload_at = None;
+                    let Some(matches) = &matches else {
+                        continue;
+                    };
+                    let old = args.listener_options();
+                    if let Err(e) = args.reload(matches) {
+                        error!("Failed to reload the config file: {e}");
+                        continue;
+                    }
+                    position_poll = args.position_poll();
+                    uploader.resize = args.resize();
+                    uploader.retries = args.upload_retries;
+                    uploader.failures_max = args.upload_failures_max;
+                    uploader.pause = Duration::from_secs(args.upload_pause_secs);
+                    uploader.retry_ms = (args.retry_base_ms, args.retry_max_ms);
+                    uploader.max_bytes = args.max_upload_bytes;
+                    uploader.verify = args.verify_upload;
+                    uploader.trusted_art_hosts = args.trusted_art_hosts.clone();
+                    uploader.rehost_untrusted = args.rehost_untrusted_art;
+                    uploader.only_local = args.cover_only_local;
+                    uploader.temp_dir = args.temp_dir.clone();
+                    uploader.static_image = args.static_large_image.is_some();
+                    uploader.embedded_art = args.embedded_art();
+                    // Covers might be resized differently now
+                    uploader.recent =
+                        Arc::new(Mutex::new(RecentCovers::new(args.cover_cache_ttl())));
+                    if old != args.listener_options() {
+                        info!("Restarting playerctl for players {}", args.players().join(","));
+                        listener.abort();
+                        listener = spawn_listener(sx.clone(), &args, uploader.clone());
+                    }
+                    // Re-render the current activity with the new options
+                    if let Some(Err(e)) = args.shown.then(|| args.set_activity()) {
+                        error!("Failed to update the activity after reloading: {e}");
+                        args.client = None;
+                        reconnect_at.get_or_insert_with(|| Instant::now() + reconnect_delay);
+                    }
+                    continue;
+                }
+                _ = position_poll.tick(),
+                    if args.position_poll_secs > 0 && args.shown && !args.track.is_paused() =>
+                {
+                    match args.poll_position().await {
+                        Ok(true) => {
+                            if let Err(e) = args.set_activity() {
+                                error!("Failed to update the activity: {e}");
+                                args.client = None;
+                                reconnect_at.get_or_insert_with(|| Instant::now() + reconnect_delay);
+                            }
+                        }
+                        Ok(false) => {}
+                        Err(e) => debug!("Failed to poll the playback position: {e}"),
+                    }
+                    continue;
+                }
+                _ = sleep_until(args.next_update().unwrap_or_else(Instant::now)),
+                    if args.next_update().is_some() =>
+                {
+                    if let Err(e) = args.set_activity() {
+                        error!("Failed to update the activity: {e}");
+                        args.client = None;
+                        reconnect_at.get_or_insert_with(|| Instant::now() + reconnect_delay);
+                    }
+                    continue;
+                }
+                _ = sleep_until(hold.at().unwrap_or_else(Instant::now)), if hold.at().is_some() => {
+                    let Some((track, cover)) = hold.release() else {
+                        continue;
+                    };
+                    if let Some(cover) = cover {
+                        let _ = sx.send(cover);
+                    }
+                    released = true;
+                    TrackUpdate::New(track)
+                }
+                _ = sleep_until(reconnect_at.unwrap_or_else(Instant::now)),
+                    if reconnect_at.is_some() =>
+                {
+                    #[cfg(feature = "metrics")]
+                    metrics::RECONNECTS.inc();
+                    match args.set_activity() {
+                        Ok(()) => {
+                            info!("Connected to Discord");
+                            connected = true;
+                            reconnect_at = None;
+                            reconnect_delay = RECONNECT_DELAY_MIN;
+                        }
+                        Err(e) => {
+                            args.client = None;
+                            if !connected
+                                && args.connect_timeout > 0
+                                && started.elapsed() >= Duration::from_secs(args.connect_timeout)
+                            {
+                                return Err(format!(
+                                    "Discord did not become available within {}s: {e}",
+                                    args.connect_timeout
+                                )
+                                .into());
+                            }
+                            reconnect_delay = (reconnect_delay * 2).min(RECONNECT_DELAY_MAX);
+                            warn!(
+                                "Failed to reconnect to Discord, retrying in {}s: {e}",
+                                reconnect_delay.as_secs()
+                            );
+                            reconnect_at = Some(Instant::now() + reconnect_delay);
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            if !released {
+                if let TrackUpdate::New(track)
+                | TrackUpdate::ImageUploaded { track, .. } = &mut update
+                {
+                    args.prepare(track);
+                }
+                for scrobbler in &scrobblers {
+                    let _ = scrobbler.send(update.clone());
+                }
+            }
+            if !released
+                && args.min_track_secs > 0
+                && hold.hold(
+                    &update,
+                    &args.track,
+                    Duration::from_secs(args.min_track_secs),
+                )
+            {
+                continue;
+            }
+            args.handle_retrying(update).await;
+
+            connected |= args.client.is_some();
+            if args.disconnected() {
+                if reconnect_at.is_none() {
+                    if connected {
+                        info!(
+                            "Lost connection to Discord, reconnecting in the background"
+                        );
+                    } else {
+                        info!(
+                            "Discord is not available yet, waiting for it in the background"
+                        );
+                    }
+                    reconnect_at = Some(Instant::now() + reconnect_delay);
+                }
+            } else {
+                reconnect_at = None;
+                reconnect_delay = RECONNECT_DELAY_MIN;
+            }
+            #[cfg(feature = "systemd")]
+            if !ready {
+                ready = true;
+                systemd::notify(NotifyState::Ready);
+            }
+        }
+
+        #[cfg(feature = "systemd")]
+        systemd::notify(NotifyState::Stopping);
+
+        if let Err(e) = args.clear_activity() {
+            warn!("Failed to clear the activity: {e}");
+        }
+        args.write_now_playing(String::new());
+        // Closing the channels makes the scrobblers submit what is left
+        drop(scrobblers);
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        for task in scrobbler_tasks {
+            if timeout_at(deadline, task).await.is_err() {
+                warn!("Scrobblers did not finish in time, some scrobbles may be lost");
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A new track held back by `--min-track-secs`, so tracks skipped right away are
+/// never shown
+#[derive(Default)]
+struct Hold {
+    /// The held back track and when to show it
+    track: Option<(Instant, TrackInfo)>,
+    /// Its cover, sent once it is released
+    cover: Option<TrackUpdate>,
+}
+
+impl Hold {
+    /// Holds back `update` if it is a new track, or the cover of the held back
+    /// one. Anything else drops the held back track, except for the covers of
+    /// other tracks.
+    fn hold(&mut self, update: &TrackUpdate, shown: &TrackInfo, wait: Duration) -> bool {
+        match update {
+            TrackUpdate::New(track) if !track.is_paused() && track != shown => {
+                match &mut self.track {
+                    // Seeked or changed its cover, which doesn't restart the wait
+                    Some((_, held)) if held == track => *held = track.clone(),
+                    _ => {
+                        self.track = Some((Instant::now() + wait, track.clone()));
+                        self.cover = None;
+                    }
+                }
+                true
+            }
+            TrackUpdate::ImageUploaded { track, .. } => {
+                let held = self.track.as_ref().is_some_and(|(_, held)| held == track);
+                if held {
+                    self.cover = Some(update.clone());
+                }
+                held
+            }
+            _ => {
+                self.track = None;
+                self.cover = None;
+                false
+            }
+        }
+    }
+
+    /// When to show the held back track
+    fn at(&self) -> Option<Instant> {
+        self.track.as_ref().map(|(at, _)| *at)
+    }
+
+    /// The held back track and its cover, if that was uploaded already
+    fn release(&mut self) -> Option<(TrackInfo, Option<TrackUpdate>)> {
+        let (_, track) = self.track.take()?;
+        Some((track, self.cover.take()))
+    }
+}
+
+/// Installs a handler for `kind`
+fn handle_signal(kind: SignalKind, name: &str) -> Result<Signal, String> {
+    signal(kind).map_err(|e| format!("Failed to install the {name} handler: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(title: &str) -> TrackInfo {
+        TrackInfo {
+            title: title.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn held_tracks_keep_their_cover() {
+        let mut hold = Hold::default();
+        let shown = track("Shown");
+        let wait = Duration::from_secs(10);
+        let cover = TrackUpdate::ImageUploaded {
+            track: track("Next"),
+            url: "https://example.com/cover.png".to_string(),
+        };
+
+        assert!(hold.hold(&TrackUpdate::New(track("Next")), &shown, wait));
+        let at = hold.at();
+        assert!(hold.hold(&cover, &shown, wait));
+        let mut seeked = track("Next");
+        seeked.position = 5_000_000;
+        assert!(hold.hold(&TrackUpdate::New(seeked), &shown, wait));
+        assert_eq!(hold.at(), at);
+
+        let (track, cover) = hold.release().unwrap();
+        assert_eq!(track.position, 5_000_000);
+        let Some(TrackUpdate::ImageUploaded { url, .. }) = cover else {
+            panic!("the cover got lost: {cover:?}");
+        };
+        assert_eq!(url, "https://example.com/cover.png");
+        assert_eq!(hold.at(), None);
+    }
+}
@@ -0,0 +1,134 @@
+use std::ffi::OsStr;
+
+use clap::builder::TypedValueParser;
+
+#[derive(Clone)]
+pub struct SizeParser;
+
+/// Size track covers get resized to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Size {
+    /// Width and height in pixels, 0 to derive one from the other by keeping the
+    /// aspect ratio
+    Fixed(u32, u32),
+    /// Relative to the size of the cover
+    Percent(u32),
+}
+
+impl Size {
+    /// The size a `width`x`height` cover gets resized to
+    pub fn target(self, width: u32, height: u32) -> (u32, u32) {
+        let scale = |value: u32, to: u32, of: u32| {
+            (value as u64 * to as u64 / of.max(1) as u64).max(1) as u32
+        };
+        match self {
+            Size::Fixed(0, to) => (scale(width, to, height), to),
+            Size::Fixed(to, 0) => (to, scale(height, to, width)),
+            Size::Fixed(x, y) => (x, y),
+            Size::Percent(percent) => {
+                (scale(width, percent, 100), scale(height, percent, 100))
+            }
+        }
+    }
+
+    /// The size a `width`x`height` cover is shrunk to, `None` if it already fits.
+    /// Covers are only ever downscaled, and keep their aspect ratio when resized.
+    pub fn shrink(self, width: u32, height: u32) -> Option<(u32, u32)> {
+        let (target_width, target_height) = self.target(width, height);
+        (width > target_width || height > target_height)
+            .then_some((target_width, target_height))
+    }
+}
+
+impl TypedValueParser for SizeParser {
+    type Value = Size;
+
+    fn parse_ref(
+        &self,
+        _cmd: &clap::Command,
+        _arg: Option<&clap::Arg>,
+        value: &OsStr,
+    ) -> Result<Self::Value, clap::Error> {
+        let value = value
+            .to_str()
+            .ok_or(clap::Error::new(clap::error::ErrorKind::InvalidUtf8))?;
+        parse_size(value).ok_or(clap::Error::new(clap::error::ErrorKind::ValueValidation))
+    }
+}
+
+/// Parses a `{width}x{height}`, `{size}` or `{percent}%` string
+pub fn parse_size(value: &str) -> Option<Size> {
+    if let Some(percent) = value.strip_suffix('%') {
+        return percent.parse().ok().filter(|&p| p > 0).map(Size::Percent);
+    }
+    let (x, y) = match value.split_once('x') {
+        Some((x, y)) => (x.parse().ok()?, y.parse().ok()?),
+        None => {
+            let size = value.parse().ok()?;
+            (size, size)
+        }
+    };
+    ((x, y) != (0, 0)).then_some(Size::Fixed(x, y))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_sizes_are_kept() {
+        assert_eq!(Size::Fixed(300, 200).target(2000, 2000), (300, 200));
+        assert_eq!(Size::Fixed(300, 200).target(100, 50), (300, 200));
+    }
+
+    #[test]
+    fn zero_keeps_the_aspect_ratio() {
+        assert_eq!(Size::Fixed(0, 150).target(2000, 1000), (300, 150));
+        assert_eq!(Size::Fixed(150, 0).target(2000, 1000), (150, 75));
+        // Never scaled down to nothing, even for odd shapes
+        assert_eq!(Size::Fixed(0, 10).target(1, 1000), (1, 10));
+        assert_eq!(Size::Fixed(10, 0).target(0, 0), (10, 1));
+    }
+
+    #[test]
+    fn percentages_scale_both_sides() {
+        assert_eq!(Size::Percent(50).target(2000, 1000), (1000, 500));
+        assert_eq!(Size::Percent(1).target(50, 50), (1, 1));
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("300x200"), Some(Size::Fixed(300, 200)));
+        assert_eq!(parse_size("150"), Some(Size::Fixed(150, 150)));
+        assert_eq!(parse_size("0x150"), Some(Size::Fixed(0, 150)));
+        assert_eq!(parse_size("150x0"), Some(Size::Fixed(150, 0)));
+        assert_eq!(parse_size("50%"), Some(Size::Percent(50)));
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        for size in [
+            "", "0", "0x0", "0%", "x150", "150x", "-1", "1.5", "50 %", "axb",
+        ] {
+            assert_eq!(parse_size(size), None, "{size}");
+        }
+    }
+
+    #[test]
+    fn oversized_covers_are_shrunk() {
+        assert_eq!(Size::Fixed(300, 300).shrink(2000, 2000), Some((300, 300)));
+        // Either side being too large is enough
+        assert_eq!(Size::Fixed(300, 300).shrink(400, 200), Some((300, 300)));
+        assert_eq!(Size::Fixed(0, 150).shrink(600, 300), Some((300, 150)));
+        assert_eq!(Size::Percent(50).shrink(400, 200), Some((200, 100)));
+    }
+
+    #[test]
+    fn small_covers_are_kept() {
+        assert_eq!(Size::Fixed(300, 300).shrink(100, 200), None);
+        assert_eq!(Size::Fixed(300, 300).shrink(300, 300), None);
+        assert_eq!(Size::Fixed(300, 0).shrink(300, 500), None);
+        assert_eq!(Size::Percent(100).shrink(400, 200), None);
+        assert_eq!(Size::Percent(200).shrink(400, 200), None);
+    }
+}
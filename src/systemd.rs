@@ -0,0 +1,11 @@
+use log::{debug, warn};
+use sd_notify::NotifyState;
+
+/// Tells systemd about the state of the service, see sd_notify(3). Does nothing
+/// unless started by systemd.
+pub fn notify(state: NotifyState) {
+    debug!("Notifying systemd: {state}");
+    if let Err(e) = sd_notify::notify(false, &[state]) {
+        warn!("Failed to notify systemd: {e}");
+    }
+}
@@ -0,0 +1,668 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+use regex::Regex;
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use urlencoding::{decode, encode};
+
+/// playerctl metadata fields, in the order `TrackInfo::parse` expects them
+pub const FIELDS: [&str; 13] = [
+    "mpris:trackid",
+    "title",
+    "artist",
+    "album",
+    "mpris:artUrl",
+    "mpris:length",
+    "position",
+    "status",
+    "playerName",
+    "loop",
+    "shuffle",
+    "xesam:trackNumber",
+    "xesam:url",
+];
+/// ASCII unit separator, put between the fields
+pub const FIELD_SEPARATOR: char = '\x1f';
+/// ASCII record separator, put after the last field
+pub const RECORD_SEPARATOR: char = '\x1e';
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(from = "TrackJson")]
+pub struct TrackInfo {
+    /// `mpris:trackid`, empty if the player doesn't report a usable one
+    pub track_id: String,
+    pub title: String,
+    /// The title as reported by the player, before `clean_title`
+    pub raw_title: String,
+    pub artists: Vec<String>,
+    /// `artists` joined for display, see `join_artists`
+    pub artist: String,
+    pub album: String,
+    pub art_url: String,
+    pub player: String,
+    pub art_kind: ArtKind,
+    /// Time at which the metadata was received, in milliseconds since the epoch
+    pub start: i64,
+    /// Length of the track in microseconds
+    pub length: i64,
+    /// Playback position in microseconds at the time the metadata was received
+    pub position: i64,
+    pub status: PlaybackStatus,
+    pub loop_status: LoopStatus,
+    pub shuffle: bool,
+    /// `xesam:trackNumber`, the position of the track on its album, 0 if unknown
+    pub track_number: u16,
+    /// Number of tracks on the album, only known (not 0) if the player reports the
+    /// track number like `3/12`
+    pub track_total: u16,
+}
+
+/// The format of [`TrackInfo::to_json`], which every field may be missing from.
+/// `artists` takes precedence over `artist`, and `status` over `paused`.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct TrackJson {
+    title: String,
+    artist: String,
+    artists: Vec<String>,
+    album: String,
+    art_url: String,
+    player: String,
+    status: Option<String>,
+    paused: bool,
+    #[serde(rename = "loop")]
+    loop_status: String,
+    shuffle: bool,
+    track_number: Option<u16>,
+    track_total: Option<u16>,
+    /// In microseconds, like `length`
+    position: i64,
+    length: i64,
+}
+
+impl From<TrackJson> for TrackInfo {
+    fn from(json: TrackJson) -> Self {
+        let artists = match json.artists.is_empty() {
+            true => (!json.artist.is_empty())
+                .then_some(json.artist)
+                .into_iter()
+                .collect(),
+            false => json.artists,
+        };
+        let player = match json.player.is_empty() {
+            true => "stdin",
+            false => &json.player,
+        };
+        let status = match (json.status, json.paused) {
+            (Some(status), _) => PlaybackStatus::parse(&status),
+            (None, true) => PlaybackStatus::Paused,
+            (None, false) => PlaybackStatus::Playing,
+        };
+        TrackInfo {
+            length: json.length,
+            position: json.position,
+            status,
+            loop_status: LoopStatus::parse(&json.loop_status),
+            shuffle: json.shuffle,
+            track_number: json.track_number.unwrap_or_default(),
+            track_total: json.track_total.unwrap_or_default(),
+            ..TrackInfo::new(&json.title, artists, &json.album, &json.art_url, player)
+        }
+    }
+}
+
+/// MPRIS `PlaybackStatus`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum PlaybackStatus {
+    #[default]
+    Playing,
+    /// Shown with a frozen position, see `--hide-on-pause`
+    Paused,
+    /// Never shown
+    Stopped,
+}
+
+impl PlaybackStatus {
+    /// Anything unknown counts as paused, like everything but `Playing` used to.
+    /// The case is ignored, so [`as_str`](Self::as_str) is accepted as well.
+    pub fn parse(status: &str) -> Self {
+        match status.to_ascii_lowercase().as_str() {
+            "playing" => PlaybackStatus::Playing,
+            "stopped" => PlaybackStatus::Stopped,
+            _ => PlaybackStatus::Paused,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PlaybackStatus::Playing => "playing",
+            PlaybackStatus::Paused => "paused",
+            PlaybackStatus::Stopped => "stopped",
+        }
+    }
+}
+
+/// MPRIS `LoopStatus`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum LoopStatus {
+    /// Also used for players without loop support
+    #[default]
+    None,
+    Track,
+    Playlist,
+}
+
+impl LoopStatus {
+    pub fn parse(status: &str) -> Self {
+        match status.to_ascii_lowercase().as_str() {
+            "track" => LoopStatus::Track,
+            "playlist" => LoopStatus::Playlist,
+            _ => LoopStatus::None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LoopStatus::None => "none",
+            LoopStatus::Track => "track",
+            LoopStatus::Playlist => "playlist",
+        }
+    }
+
+    /// Rendered for `{loop}`
+    fn symbol(self) -> &'static str {
+        match self {
+            LoopStatus::None => "",
+            LoopStatus::Track => "🔂",
+            LoopStatus::Playlist => "🔁",
+        }
+    }
+}
+
+/// Where `TrackInfo::art_url` points to
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ArtKind {
+    /// An https url Discord can fetch on its own
+    #[default]
+    Remote,
+    /// A path to a local file
+    Local,
+    /// A `data:` uri containing the image itself
+    Data,
+    /// An http(s) url of a host not in `--trusted-art-hosts`, which Discord might
+    /// not be able to fetch
+    Untrusted,
+    /// The path of the played audio file, whose tags might contain a cover
+    Embedded,
+}
+
+impl ArtKind {
+    /// Whether the cover has to be uploaded before Discord can display it
+    pub fn needs_upload(self) -> bool {
+        matches!(
+            self,
+            ArtKind::Local | ArtKind::Data | ArtKind::Untrusted | ArtKind::Embedded
+        )
+    }
+}
+
+/// Decodes the percent-encoded path of a `file://` url, like `My%20Music`. A `+`
+/// is a plus in urls, not a space. Paths that aren't UTF-8 once decoded are kept
+/// as they are.
+fn decode_path(path: &str) -> String {
+    // `file://localhost/...` is the same as `file:///...`
+    let path = path.strip_prefix("localhost").unwrap_or(path);
+    match decode(path) {
+        Ok(decoded) => decoded.into_owned(),
+        Err(e) => {
+            warn!("Cover path {path} is not valid UTF-8 once decoded: {e}");
+            path.to_string()
+        }
+    }
+}
+
+impl TrackInfo {
+    /// Builds a track from the [`FIELDS`] separated by [`FIELD_SEPARATOR`].
+    /// Returns `None` if the number of fields doesn't match.
+    pub fn parse(record: &str) -> Option<Self> {
+        let fields: Vec<&str> = record.split(FIELD_SEPARATOR).collect();
+        let [
+            track_id,
+            title,
+            artist,
+            album,
+            art_url,
+            length,
+            position,
+            status,
+            player,
+            loop_status,
+            shuffle,
+            track_number,
+            url,
+        ] = fields[..]
+        else {
+            warn!("Ignoring malformed playerctl output: {record:?}");
+            return None;
+        };
+        // playerctl joins the `xesam:artist` list with commas, which can't be told
+        // apart from those in names like "Tyler, The Creator"
+        let artists = (!artist.is_empty())
+            .then(|| artist.to_string())
+            .into_iter()
+            .collect();
+        let (track_number, track_total) = Self::parse_track_number(track_number);
+        let mut track = TrackInfo {
+            track_id: Self::valid_track_id(track_id.to_string()),
+            length: length.parse().unwrap_or_default(),
+            position: position.parse().unwrap_or_default(),
+            status: PlaybackStatus::parse(status),
+            loop_status: LoopStatus::parse(loop_status),
+            shuffle: shuffle == "true",
+            track_number,
+            track_total,
+            ..Self::new(title, artists, album, art_url, player)
+        };
+        track.use_embedded_art(url);
+        Some(track)
+    }
+
+    /// A playing track with unknown length, received just now
+    pub fn new(
+        title: &str,
+        artists: Vec<String>,
+        album: &str,
+        art_url: &str,
+        player: &str,
+    ) -> Self {
+        let art_url = art_url.to_string();
+
+        let (art_url, art_kind) = if let Some(file) = art_url.strip_prefix("file://") {
+            (decode_path(file), ArtKind::Local)
+        } else if art_url.starts_with("data:") {
+            (art_url, ArtKind::Data)
+        } else if art_url.is_empty()
+            || art_url.starts_with("https://")
+            || art_url.starts_with("http://")
+        {
+            (art_url, ArtKind::Remote)
+        } else {
+            let scheme = art_url.split(':').next().unwrap_or_default();
+            warn!("Ignoring cover url with unsupported scheme `{scheme}`");
+            (String::new(), ArtKind::Remote)
+        };
+
+        TrackInfo {
+            track_id: String::new(),
+            title: title.to_string(),
+            raw_title: title.to_string(),
+            artist: artists.join(", "),
+            artists,
+            album: album.to_string(),
+            player: player.to_string(),
+            art_url,
+            art_kind,
+            start: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as i64,
+            length: 0,
+            position: 0,
+            status: PlaybackStatus::Playing,
+            loop_status: LoopStatus::None,
+            shuffle: false,
+            track_number: 0,
+            track_total: 0,
+        }
+    }
+
+    /// Falls back to the cover embedded in the played file if the player reports
+    /// none and `url` is a `file://` url
+    pub fn use_embedded_art(&mut self, url: &str) {
+        if let Some(file) = url.strip_prefix("file://")
+            && self.art_url.is_empty()
+        {
+            self.art_url = decode_path(file);
+            self.art_kind = ArtKind::Embedded;
+        }
+    }
+
+    /// Parses a track number like `3`, or `3/12` including the number of tracks.
+    /// Anything unparsable is 0, which means unknown.
+    pub fn parse_track_number(value: &str) -> (u16, u16) {
+        let (number, total) = value.split_once('/').unwrap_or((value, ""));
+        let parse = |n: &str| n.trim().parse().unwrap_or_default();
+        (parse(number), parse(total))
+    }
+
+    /// `id`, unless it is the `NoTrack` placeholder some players report for every
+    /// track
+    pub fn valid_track_id(id: String) -> String {
+        match id.as_str() {
+            "/org/mpris/MediaPlayer2/TrackList/NoTrack" => String::new(),
+            _ => id,
+        }
+    }
+
+    /// The player without its instance suffix, like `vlc` for `vlc.instance1234`
+    pub fn player_name(&self) -> &str {
+        self.player.split('.').next().unwrap_or_default()
+    }
+
+    /// Replaces the `{title}`, `{artist}`, `{album}`, `{player}`, `{loop}`,
+    /// `{shuffle}`, `{track_number}` and `{track_total}` placeholders in `template`
+    pub fn render(&self, template: &str) -> String {
+        self.render_with(template, str::to_string)
+    }
+
+    /// Like [`render`](Self::render), but url-encodes the replacements
+    pub fn render_url(&self, template: &str) -> String {
+        self.render_with(template, |value| encode(value).into_owned())
+    }
+
+    fn render_with(&self, template: &str, escape: impl Fn(&str) -> String) -> String {
+        // 0 means unknown
+        let number = |n: u16| match n {
+            0 => String::new(),
+            n => n.to_string(),
+        };
+        template
+            .replace("{title}", &escape(&self.title))
+            .replace("{artist}", &escape(&self.artist))
+            .replace("{album}", &escape(&self.album))
+            .replace("{player}", &escape(self.player_name()))
+            .replace("{loop}", &escape(self.loop_status.symbol()))
+            .replace("{shuffle}", &escape(if self.shuffle { "🔀" } else { "" }))
+            .replace("{track_number}", &number(self.track_number))
+            .replace("{track_total}", &number(self.track_total))
+    }
+
+    /// Takes the loop and shuffle modes of `other`, a later report of the same
+    /// track
+    pub fn update_modes(&mut self, other: &TrackInfo) {
+        self.loop_status = other.loop_status;
+        self.shuffle = other.shuffle;
+    }
+
+    /// Removes every match of `patterns` from the title until none matches anymore
+    pub fn clean_title(&mut self, patterns: &[Regex]) {
+        loop {
+            let cleaned = patterns.iter().fold(self.title.clone(), |title, pattern| {
+                pattern.replace_all(&title, "").into_owned()
+            });
+            if cleaned == self.title {
+                break;
+            }
+            self.title = cleaned;
+        }
+    }
+
+    /// Splits titles like `Artist - Song` on `separator` if the player reported no
+    /// artist. Titles containing it more than once are ambiguous and kept.
+    pub fn split_title(&mut self, separator: &str) {
+        if !self.artist.is_empty() || self.title.matches(separator).count() != 1 {
+            return;
+        }
+        let split = self
+            .title
+            .split_once(separator)
+            .map(|(artist, title)| (artist.trim(), title.trim()))
+            .filter(|(artist, title)| !artist.is_empty() && !title.is_empty())
+            .map(|(artist, title)| (artist.to_string(), title.to_string()));
+        if let Some((artist, title)) = split {
+            (self.artist, self.title) = (artist.clone(), title);
+            self.artists = vec![artist];
+        }
+    }
+
+    /// The metadata as reported by `--once` and the control socket
+    pub fn to_json(&self) -> Value {
+        json!({
+            "title": self.title,
+            "artist": self.artist,
+            "artists": self.artists,
+            "album": self.album,
+            "art_url": self.art_url,
+            "player": self.player,
+            "paused": self.is_paused(),
+            "status": self.status.as_str(),
+            "loop": self.loop_status.as_str(),
+            "shuffle": self.shuffle,
+            "track_number": (self.track_number > 0).then_some(self.track_number),
+            "track_total": (self.track_total > 0).then_some(self.track_total),
+            "position": self.current_position(),
+            "length": self.length,
+        })
+    }
+
+    /// Moves the playback position to `position` microseconds, as of now
+    pub fn seek(&mut self, position: i64) {
+        self.position = position;
+        self.start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+    }
+
+    /// Whether the track isn't playing, because it is either paused or stopped
+    pub fn is_paused(&self) -> bool {
+        self.status != PlaybackStatus::Playing
+    }
+
+    /// Whether this is a live stream or radio, which have no length
+    pub fn is_live(&self) -> bool {
+        self.length <= 0
+    }
+
+    /// Playback position in microseconds, as of now
+    pub fn current_position(&self) -> i64 {
+        if self.is_paused() {
+            return self.position;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        self.position + (now - self.start) * 1000
+    }
+
+    /// Sets `artist` to `artists` joined with `separator`
+    pub fn join_artists(&mut self, separator: &str) {
+        self.artist = self.artists.join(separator);
+    }
+
+    /// Marks remote covers of hosts other than `trusted_hosts` and their
+    /// subdomains to be uploaded. Those of trusted hosts are kept, but upgraded to
+    /// https, as Discord only accepts https urls.
+    pub fn check_art_host(&mut self, trusted_hosts: &[String]) {
+        if self.art_kind != ArtKind::Remote || self.art_url.is_empty() {
+            return;
+        }
+        let host = Url::parse(&self.art_url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string));
+        let trusted = host.is_some_and(|host| {
+            trusted_hosts
+                .iter()
+                .any(|trusted| host == *trusted || host.ends_with(&format!(".{trusted}")))
+        });
+        if !trusted {
+            debug!("Uploading cover {} of untrusted host", self.art_url);
+            self.art_kind = ArtKind::Untrusted;
+        } else if let Some(url) = self.art_url.strip_prefix("http://") {
+            debug!("Upgrading cover url {} to https", self.art_url);
+            self.art_url = format!("https://{url}");
+        }
+    }
+
+    /// Takes the cover of `other`, a later report of the same track. A local cover
+    /// that is still the same file keeps its uploaded url, a changed one is
+    /// replaced once its upload is done.
+    pub fn update_art(&mut self, other: &TrackInfo) {
+        let changed = match other.art_kind {
+            ArtKind::Remote => other.art_url != self.art_url,
+            _ => other.art_kind != self.art_kind,
+        };
+        if changed {
+            debug!("Cover of {} changed to {}", self.title, other.art_url);
+            self.art_url = other.art_url.clone();
+            self.art_kind = other.art_kind;
+        }
+    }
+}
+
+/// Whether two reports are of the same track, going by the track id if both have
+/// one, so identical copies in a playlist still count as different tracks. Radio
+/// streams keep their id while the song changes, so they are compared by metadata.
+/// The cover isn't part of that, as players tend to report it a moment after the
+/// rest of the metadata, and ours is replaced by the uploaded url.
+impl PartialEq for TrackInfo {
+    fn eq(&self, other: &Self) -> bool {
+        if !self.track_id.is_empty() && !other.track_id.is_empty() && !self.is_live() {
+            return self.player == other.player && self.track_id == other.track_id;
+        }
+        self.title == other.title
+            && self.artists == other.artists
+            && self.album == other.album
+            && self.length == other.length
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(title: &str) -> String {
+        [
+            "/id/1",
+            title,
+            "Artist, Other",
+            "Album",
+            "",
+            "180000000",
+            "5000000",
+            "Paused",
+            "kew",
+            "Playlist",
+            "true",
+            "3/12",
+            "",
+        ]
+        .join(&FIELD_SEPARATOR.to_string())
+    }
+
+    #[test]
+    fn parses_fields() {
+        let track = TrackInfo::parse(&record("Song")).unwrap();
+        assert_eq!(track.track_id, "/id/1");
+        assert_eq!(track.title, "Song");
+        assert_eq!(track.artists, ["Artist, Other"]);
+        assert_eq!(track.artist, "Artist, Other");
+        assert_eq!(track.album, "Album");
+        assert_eq!(track.length, 180_000_000);
+        assert_eq!(track.position, 5_000_000);
+        assert!(track.is_paused());
+        assert_eq!(track.loop_status, LoopStatus::Playlist);
+        assert!(track.shuffle);
+        assert_eq!((track.track_number, track.track_total), (3, 12));
+    }
+
+    #[test]
+    fn titles_are_taken_verbatim() {
+        for title in [
+            r#"She Said "Yes""#,
+            r"Back\slash \n",
+            "First line\nSecond line",
+            "{\"title\": null}",
+        ] {
+            assert_eq!(TrackInfo::parse(&record(title)).unwrap().title, title);
+        }
+    }
+
+    #[test]
+    fn malformed_records_are_ignored() {
+        assert!(TrackInfo::parse("").is_none());
+        let record = record("Song");
+        let (fewer, _) = record.rsplit_once(FIELD_SEPARATOR).unwrap();
+        assert!(TrackInfo::parse(fewer).is_none());
+        assert!(TrackInfo::parse(&format!("{record}{FIELD_SEPARATOR}")).is_none());
+    }
+
+    fn track(id: &str, title: &str, art_url: &str) -> TrackInfo {
+        TrackInfo {
+            track_id: id.to_string(),
+            length: 180_000_000,
+            ..TrackInfo::new(title, vec!["Artist".into()], "Album", art_url, "kew")
+        }
+    }
+
+    #[test]
+    fn equal_by_track_id() {
+        assert_eq!(track("/id/1", "Song", ""), track("/id/1", "Renamed", ""));
+        // Copies of a track in a playlist
+        assert_ne!(track("/id/1", "Song", ""), track("/id/2", "Song", ""));
+    }
+
+    #[test]
+    fn equal_by_metadata_without_track_id() {
+        assert_eq!(track("", "Song", ""), track("/id/1", "Song", ""));
+        assert_ne!(track("", "Song", ""), track("", "Other", ""));
+        let mut longer = track("", "Song", "");
+        longer.length += 1;
+        assert_ne!(track("", "Song", ""), longer);
+    }
+
+    #[test]
+    fn cover_changes_keep_the_track() {
+        let uploaded = track("", "Song", "https://example.com/cover.png");
+        assert_eq!(track("", "Song", "file:///cover.png"), uploaded);
+        assert_eq!(track("/id/1", "Song", ""), track("/id/1", "Song", "data:,"));
+    }
+
+    #[test]
+    fn streams_are_compared_by_metadata() {
+        let stream = |title| TrackInfo {
+            length: 0,
+            ..track("/stream", title, "")
+        };
+        assert_eq!(stream("Song"), stream("Song"));
+        assert_ne!(stream("Song"), stream("Next song"));
+    }
+
+    #[test]
+    fn player_is_rendered_without_instance() {
+        let track = TrackInfo::new("Song", vec![], "", "", "vlc.instance1234");
+        assert_eq!(track.render("on {player}"), "on vlc");
+        let track = TrackInfo::new("Song", vec![], "", "", "Cool Player");
+        assert_eq!(track.render_url("?from={player}"), "?from=Cool%20Player");
+    }
+
+    #[test]
+    fn file_urls_are_decoded() {
+        for (url, path) in [
+            (
+                "file:///home/me/My%20Music/cover.jpg",
+                "/home/me/My Music/cover.jpg",
+            ),
+            (
+                "file:///music/Bj%C3%B6rk/%E6%97%A5%E6%9C%AC.png",
+                "/music/Björk/日本.png",
+            ),
+            ("file:///music/Björk/cover.png", "/music/Björk/cover.png"),
+            ("file:///music/A+B/cover.png", "/music/A+B/cover.png"),
+            ("file://localhost/music/cover%2B.png", "/music/cover+.png"),
+        ] {
+            let track = TrackInfo::new("Song", vec![], "", url, "kew");
+            assert_eq!(track.art_url, path);
+            assert_eq!(track.art_kind, ArtKind::Local);
+        }
+    }
+
+    #[test]
+    fn undecodable_paths_are_kept() {
+        let track = TrackInfo::new("Song", vec![], "", "file:///music/%FF.png", "kew");
+        assert_eq!(track.art_url, "/music/%FF.png");
+    }
+}